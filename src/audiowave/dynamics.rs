@@ -0,0 +1,285 @@
+//! Processors changing the level of a wave depending on the level of the signal.
+
+use super::{db_to_gain, gain_to_db, AudioWave};
+use crate::definitions::{to_f64, Float};
+use std::collections::VecDeque;
+
+/// A brickwall limiter: no sample goes above `ceiling`, and the gain starts going down
+/// `lookahead` seconds before a peak so it doesn't distort it.
+#[derive(Clone, Copy, Debug)]
+pub struct Limiter {
+    /// Highest level of the output, in dBFS.
+    pub ceiling: Float,
+    /// In seconds.
+    pub lookahead: Float,
+    /// Time the gain takes to come back up after a peak, in seconds.
+    pub release: Float,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter {
+            ceiling: 0.0,
+            lookahead: 0.005,
+            release: 0.05,
+        }
+    }
+}
+
+/// Turns down the part of the signal above `threshold` by `ratio`: with a ratio of 4, going
+/// 8 dB above the threshold only makes the output go 2 dB above it.
+#[derive(Clone, Copy, Debug)]
+pub struct Compressor {
+    /// In dBFS.
+    pub threshold: Float,
+    pub ratio: Float,
+    /// Time taken to react to the signal getting louder, in seconds.
+    pub attack: Float,
+    /// Time taken to react to the signal getting quieter, in seconds.
+    pub release: Float,
+    /// Gain applied after compressing, in dB.
+    pub makeup: Float,
+}
+
+/// Silences the signal while it is below `threshold`.
+#[derive(Clone, Copy, Debug)]
+pub struct Gate {
+    /// In dBFS.
+    pub threshold: Float,
+    /// Time taken to open once the signal goes above the threshold, in seconds.
+    pub attack: Float,
+    /// Time taken to close once the signal goes below the threshold, in seconds.
+    pub release: Float,
+}
+
+/// Coefficient of a one-pole smoother reaching about two thirds of a step in `time` seconds.
+fn smoothing(time: Float, samplerate: u32) -> Float {
+    if time <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (time * samplerate as Float)).exp()
+    }
+}
+
+/// Level of `signal` at each of the first `length` samples, following rises with `attack`
+/// and falls with `release`. The signal is taken as silent past its end.
+fn follow_level(
+    signal: &[Float],
+    length: usize,
+    attack: Float,
+    release: Float,
+    samplerate: u32,
+) -> Vec<Float> {
+    let attack = smoothing(attack, samplerate);
+    let release = smoothing(release, samplerate);
+    let mut level = 0.0;
+    (0..length)
+        .map(|i| {
+            let x = signal.get(i).map_or(0.0, |x| x.abs());
+            let coefficient = if x > level { attack } else { release };
+            level = coefficient * level + (1.0 - coefficient) * x;
+            level
+        })
+        .collect()
+}
+
+/// Curves rounding off loud samples instead of cutting them flat. Quiet samples are left
+/// about as they are and the output never goes above 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SoftClip {
+    Tanh,
+    /// `x - 4x³/27`, reaching 1 at 1.5 with a flat slope.
+    Cubic,
+}
+
+impl SoftClip {
+    pub fn apply(&self, x: Float) -> Float {
+        match self {
+            SoftClip::Tanh => x.tanh(),
+            SoftClip::Cubic => {
+                let x = x.clamp(-1.5, 1.5);
+                x - 4.0 / 27.0 * x * x * x
+            }
+        }
+    }
+}
+
+/// For each sample, the lowest value among it and the `window - 1` following ones.
+fn minimum_ahead(values: &[Float], window: usize) -> Vec<Float> {
+    let mut result = vec![0.0; values.len()];
+    // indices of increasing values, the front being the minimum of the window
+    let mut candidates: VecDeque<usize> = VecDeque::new();
+    for i in (0..values.len()).rev() {
+        while candidates.back().is_some_and(|&j| values[j] >= values[i]) {
+            candidates.pop_back();
+        }
+        candidates.push_back(i);
+        while candidates.front().is_some_and(|&j| j >= i + window) {
+            candidates.pop_front();
+        }
+        result[i] = values[candidates[0]];
+    }
+    result
+}
+
+impl AudioWave {
+    pub fn limit(&mut self, limiter: &Limiter) {
+        let ceiling = db_to_gain(limiter.ceiling);
+        let samplerate = self.samplerate as Float;
+        let window = ((limiter.lookahead * samplerate).round() as usize).max(1);
+        let release = 1.0 - (-1.0 / (limiter.release.max(1e-6) * samplerate)).exp();
+
+        // gain each sample needs to stay below the ceiling
+        let needed: Vec<Float> = self
+            .wave
+            .iter()
+            .map(|x| {
+                if x.abs() > ceiling {
+                    ceiling / x.abs()
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+        // reaching it as soon as a peak comes into the lookahead, and slowly letting go after
+        let mut envelope = minimum_ahead(&needed, window);
+        let mut gain = 1.0;
+        for g in &mut envelope {
+            gain = if *g < gain {
+                *g
+            } else {
+                gain + (*g - gain) * release
+            };
+            *g = gain;
+        }
+        // averaging over the lookahead turns the drops into ramps. Every gain averaged for a
+        // sample is at most the one it needs, so the average is too.
+        let mut sum: f64 = 0.0;
+        for (i, sample) in self.wave.iter_mut().enumerate() {
+            sum += to_f64(envelope[i]);
+            if i >= window {
+                sum -= to_f64(envelope[i - window]);
+            }
+            let averaged = (sum / window.min(i + 1) as f64) as Float;
+            *sample = (*sample * averaged).clamp(-ceiling, ceiling);
+        }
+    }
+
+    /// Compresses the wave, following the level of `sidechain` instead of its own if given.
+    pub fn compress(&mut self, compressor: &Compressor, sidechain: Option<&AudioWave>) {
+        let detected = sidechain.map_or(&self.wave, |s| &s.wave);
+        let levels = follow_level(
+            detected,
+            self.wave.len(),
+            compressor.attack,
+            compressor.release,
+            self.samplerate,
+        );
+        let slope = 1.0 - 1.0 / compressor.ratio.max(1.0);
+        for (sample, level) in self.wave.iter_mut().zip(levels) {
+            let over = (gain_to_db(level) - compressor.threshold).max(0.0);
+            *sample *= db_to_gain(compressor.makeup - over * slope);
+        }
+    }
+
+    /// Gates the wave, opening with the level of `sidechain` instead of its own if given.
+    pub fn gate(&mut self, gate: &Gate, sidechain: Option<&AudioWave>) {
+        let detected = sidechain.map_or(&self.wave, |s| &s.wave);
+        // the level falls slowly enough not to close the gate between two peaks of a wave
+        let levels = follow_level(detected, self.wave.len(), 0.0, 0.01, self.samplerate);
+        let threshold = db_to_gain(gate.threshold);
+        let attack = smoothing(gate.attack, self.samplerate);
+        let release = smoothing(gate.release, self.samplerate);
+        let mut gain = 0.0;
+        for (sample, level) in self.wave.iter_mut().zip(levels) {
+            let (target, coefficient) = if level >= threshold {
+                (1.0, attack)
+            } else {
+                (0.0, release)
+            };
+            gain = coefficient * gain + (1.0 - coefficient) * target;
+            *sample *= gain;
+        }
+    }
+
+    pub fn soft_clip(&mut self, curve: SoftClip) {
+        for sample in &mut self.wave {
+            *sample = curve.apply(*sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+
+    /// A wave holding each level for each duration, one after the other, in seconds.
+    fn steps(levels: &[(Float, Float)]) -> AudioWave {
+        let samples = levels
+            .iter()
+            .flat_map(|(level, seconds)| {
+                let length = (seconds * SAMPLERATE as Float).round() as usize;
+                std::iter::repeat_n(*level, length)
+            })
+            .collect();
+        AudioWave::from_samples(samples, SAMPLERATE)
+    }
+
+    const COMPRESSOR: Compressor = Compressor {
+        threshold: -20.0,
+        ratio: 4.0,
+        attack: 0.01,
+        release: 0.1,
+        makeup: 0.0,
+    };
+
+    #[test]
+    fn compression_follows_the_ratio() {
+        for input in [-16.0, -8.0, 0.0] {
+            let mut wave = steps(&[(db_to_gain(input), 1.0)]);
+            wave.compress(&COMPRESSOR, None);
+            let expected = COMPRESSOR.threshold + (input - COMPRESSOR.threshold) / 4.0;
+            let output = gain_to_db(*wave.wave.last().unwrap());
+            assert!(
+                (output - expected).abs() < 0.01,
+                "{} dB came out at {}",
+                input,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn quiet_signals_are_not_compressed() {
+        let mut wave = steps(&[(db_to_gain(-30.0), 1.0)]);
+        let original = wave.wave.clone();
+        wave.compress(&COMPRESSOR, None);
+        assert_eq!(wave.wave, original);
+    }
+
+    #[test]
+    fn the_gate_closes_below_its_threshold() {
+        let gate = Gate {
+            threshold: -40.0,
+            attack: 0.001,
+            release: 0.01,
+        };
+        let mut wave = steps(&[(0.5, 0.5), (0.001, 0.5)]);
+        wave.gate(&gate, None);
+        let (open, closed) = wave.wave.split_at(SAMPLERATE as usize / 2);
+        assert!((open.last().unwrap() - 0.5).abs() < 1e-4);
+        assert!(closed.last().unwrap().abs() < 1e-6);
+    }
+
+    #[test]
+    fn sidechains_drive_the_gain() {
+        let mut wave = steps(&[(0.1, 1.0)]);
+        let sidechain = steps(&[(1.0, 0.5), (0.0, 0.5)]);
+        wave.compress(&COMPRESSOR, Some(&sidechain));
+        let (ducked, released) = wave.wave.split_at(SAMPLERATE as usize / 2);
+        // 0 dBFS in the sidechain is 20 dB over the threshold, turned down by 15 dB
+        assert!((gain_to_db(*ducked.last().unwrap()) - (-35.0)).abs() < 0.01);
+        assert!((released.last().unwrap() - 0.1).abs() < 1e-4);
+    }
+}
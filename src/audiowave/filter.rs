@@ -0,0 +1,227 @@
+//! Second order filters, with the formulas of Robert Bristow-Johnson's Audio EQ Cookbook.
+
+use super::AudioWave;
+use crate::definitions::{to_f64, Float};
+use std::f64::consts::PI;
+
+/// A second order IIR filter in direct form I, with coefficients normalized so `a0` is 1.
+pub struct Biquad {
+    pub b: [f64; 3],
+    pub a: [f64; 2],
+}
+
+impl Biquad {
+    pub fn filter(&self, input: &[f64]) -> Vec<f64> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        input
+            .iter()
+            .map(|&x| {
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[0] * y1
+                    - self.a[1] * y2;
+                (x2, x1, y2, y1) = (x1, x, y1, y);
+                y
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+    /// Keeps frequencies around the cutoff, the cutoff itself being left at 0 dB.
+    BandPass,
+    /// Removes frequencies around the cutoff.
+    Notch,
+    /// Boosts or cuts frequencies below the cutoff by `gain`.
+    LowShelf,
+    /// Boosts or cuts frequencies above the cutoff by `gain`.
+    HighShelf,
+    /// Boosts or cuts frequencies around the cutoff by `gain`.
+    Peak,
+}
+
+impl FilterKind {
+    pub fn from_name(name: &str) -> Option<FilterKind> {
+        match name {
+            "lowpass" => Some(FilterKind::LowPass),
+            "highpass" => Some(FilterKind::HighPass),
+            "bandpass" => Some(FilterKind::BandPass),
+            "notch" => Some(FilterKind::Notch),
+            "lowshelf" => Some(FilterKind::LowShelf),
+            "highshelf" => Some(FilterKind::HighShelf),
+            "peak" => Some(FilterKind::Peak),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    pub kind: FilterKind,
+    /// Cutoff or center frequency, in Hz.
+    pub frequency: Float,
+    /// How narrow the filter is around its frequency. 0.707 gives the flattest low-pass.
+    pub q: Float,
+    /// Boost of shelves and peaks, in dB. Other filters ignore it.
+    pub gain: Float,
+}
+
+impl Filter {
+    pub fn biquad(&self, samplerate: u32) -> Biquad {
+        let samplerate = samplerate as f64;
+        // frequencies at or above Nyquist can't be represented
+        let frequency = to_f64(self.frequency).clamp(1.0, 0.49 * samplerate);
+        let w0 = 2.0 * PI * frequency / samplerate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * to_f64(self.q).max(1e-3));
+        let a = 10f64.powf(to_f64(self.gain) / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b, a) = match self.kind {
+            FilterKind::LowPass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::HighPass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::BandPass => ([alpha, 0.0, -alpha], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            FilterKind::Notch => (
+                [1.0, -2.0 * cos, 1.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            FilterKind::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            FilterKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - shelf,
+                ],
+            ),
+            FilterKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - shelf,
+                ],
+            ),
+        };
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+        }
+    }
+}
+
+/// Several filters applied one after the other, usually shelves and peaks shaping the
+/// balance of a mix.
+#[derive(Clone, Debug, Default)]
+pub struct Equalizer {
+    pub bands: Vec<Filter>,
+}
+
+impl AudioWave {
+    pub fn filter(&mut self, filter: &Filter) {
+        let samples: Vec<f64> = self.wave.iter().map(|x| to_f64(*x)).collect();
+        let filtered = filter.biquad(self.samplerate).filter(&samples);
+        for (sample, y) in self.wave.iter_mut().zip(filtered) {
+            *sample = y as Float;
+        }
+    }
+
+    pub fn equalize(&mut self, equalizer: &Equalizer) {
+        for band in &equalizer.bands {
+            self.filter(band);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+
+    fn filter(kind: FilterKind, frequency: Float, q: Float, gain: Float) -> Filter {
+        Filter {
+            kind,
+            frequency,
+            q,
+            gain,
+        }
+    }
+
+    /// Gain of the filters on a sine at `frequency` once they have settled, in dB.
+    fn response(filters: &[Filter], frequency: f64) -> f64 {
+        let sine: Vec<f64> = (0..SAMPLERATE)
+            .map(|i| (2.0 * PI * frequency * i as f64 / SAMPLERATE as f64).sin())
+            .collect();
+        let filtered = filters
+            .iter()
+            .fold(sine.clone(), |wave, f| f.biquad(SAMPLERATE).filter(&wave));
+        // the second half holds a whole number of cycles of every tested frequency
+        let rms = |wave: &[f64]| {
+            let half = &wave[wave.len() / 2..];
+            (half.iter().map(|x| x * x).sum::<f64>() / half.len() as f64).sqrt()
+        };
+        20.0 * (rms(&filtered) / rms(&sine)).log10()
+    }
+
+    fn assert_response(filters: &[Filter], frequency: f64, expected: f64, tolerance: f64) {
+        let actual = response(filters, frequency);
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} dB at {} Hz instead of {} dB",
+            actual,
+            frequency,
+            expected
+        );
+    }
+
+    #[test]
+    fn low_pass_is_3_db_down_at_the_cutoff() {
+        let low_pass = [filter(FilterKind::LowPass, 1000.0, 0.707, 0.0)];
+        assert_response(&low_pass, 1000.0, -3.0, 0.05);
+        assert_response(&low_pass, 50.0, 0.0, 0.05);
+        // two poles take about 40 dB per decade
+        assert!(response(&low_pass, 10000.0) < -38.0);
+
+        let high_pass = [filter(FilterKind::HighPass, 1000.0, 0.707, 0.0)];
+        assert_response(&high_pass, 1000.0, -3.0, 0.05);
+        assert!(response(&high_pass, 100.0) < -38.0);
+    }
+
+    #[test]
+    fn shelves_and_peaks_reach_their_gain() {
+        let low_shelf = [filter(FilterKind::LowShelf, 500.0, 0.707, 6.0)];
+        assert_response(&low_shelf, 20.0, 6.0, 0.05);
+        assert_response(&low_shelf, 15000.0, 0.0, 0.05);
+
+        let high_shelf = [filter(FilterKind::HighShelf, 500.0, 0.707, -9.0)];
+        assert_response(&high_shelf, 15000.0, -9.0, 0.1);
+        assert_response(&high_shelf, 20.0, 0.0, 0.05);
+
+        let peak = [filter(FilterKind::Peak, 1000.0, 2.0, 12.0)];
+        assert_response(&peak, 1000.0, 12.0, 0.05);
+        assert_response(&peak, 50.0, 0.0, 0.1);
+        assert_response(&peak, 15000.0, 0.0, 0.1);
+
+        let notch = [filter(FilterKind::Notch, 1000.0, 2.0, 0.0)];
+        assert!(response(&notch, 1000.0) < -40.0);
+    }
+}
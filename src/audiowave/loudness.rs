@@ -0,0 +1,294 @@
+//! Loudness measurements following ITU-R BS.1770 and EBU R128, for a single channel.
+
+use super::filter::Biquad;
+use super::{db_to_gain, AudioWave};
+use crate::definitions::{to_f64, Float};
+use std::f64::consts::PI;
+
+/// Blocks quieter than this, in LUFS, are ignored by the integrated loudness and the loudness
+/// range.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// The two stages of the K-weighting filter, computed for any sample rate: a high shelf
+/// modelling the head, then a high-pass filter.
+fn k_weighting(samplerate: u32) -> [Biquad; 2] {
+    let samplerate = samplerate as f64;
+
+    let k = (PI * 1681.974450955533 / samplerate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    let k = (PI * 38.13547087602444 / samplerate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    [shelf, high_pass]
+}
+
+fn power_to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+impl AudioWave {
+    /// Mean power of the K-weighted signal in windows of `window` seconds starting every
+    /// 100 ms. A wave shorter than a window has no block.
+    fn block_powers(&self, window: f64) -> Vec<f64> {
+        let samplerate = self.samplerate as f64;
+        let length = (window * samplerate).round() as usize;
+        let step = (0.1 * samplerate).round() as usize;
+        if length == 0 || self.wave.len() < length {
+            return Vec::new();
+        }
+
+        let [shelf, high_pass] = k_weighting(self.samplerate);
+        let samples: Vec<f64> = self.wave.iter().map(|x| to_f64(*x)).collect();
+        let weighted = high_pass.filter(&shelf.filter(&samples));
+        // sums of the squares of the first n samples, to get the power of any block at once
+        let mut sums = Vec::with_capacity(weighted.len() + 1);
+        sums.push(0.0);
+        for x in &weighted {
+            sums.push(sums[sums.len() - 1] + x * x);
+        }
+
+        (0..=(self.wave.len() - length) / step)
+            .map(|i| (sums[i * step + length] - sums[i * step]) / length as f64)
+            .collect()
+    }
+
+    /// Loudness over the last 400 ms every 100 ms, in LUFS.
+    pub fn momentary_loudness(&self) -> Vec<Float> {
+        self.block_powers(0.4)
+            .into_iter()
+            .map(|p| power_to_lufs(p) as Float)
+            .collect()
+    }
+
+    /// Loudness over the last 3 s every 100 ms, in LUFS.
+    pub fn short_term_loudness(&self) -> Vec<Float> {
+        self.block_powers(3.0)
+            .into_iter()
+            .map(|p| power_to_lufs(p) as Float)
+            .collect()
+    }
+
+    /// Loudness of the whole wave, in LUFS, gated so silence and quiet passages don't count.
+    /// Minus infinity for silence or waves shorter than 400 ms.
+    pub fn integrated_loudness(&self) -> Float {
+        let blocks: Vec<f64> = self
+            .block_powers(0.4)
+            .into_iter()
+            .filter(|p| power_to_lufs(*p) > ABSOLUTE_GATE)
+            .collect();
+        if blocks.is_empty() {
+            return Float::NEG_INFINITY;
+        }
+        let relative_gate = power_to_lufs(mean(&blocks)) - 10.0;
+        let gated: Vec<f64> = blocks
+            .into_iter()
+            .filter(|p| power_to_lufs(*p) > relative_gate)
+            .collect();
+        power_to_lufs(mean(&gated)) as Float
+    }
+
+    /// Loudness range (LRA) in LU: the spread between the 10th and 95th percentiles of the
+    /// short-term loudness, leaving out silence and quiet passages.
+    pub fn loudness_range(&self) -> Float {
+        let blocks: Vec<f64> = self
+            .block_powers(3.0)
+            .into_iter()
+            .filter(|p| power_to_lufs(*p) > ABSOLUTE_GATE)
+            .collect();
+        if blocks.is_empty() {
+            return 0.0;
+        }
+        let relative_gate = power_to_lufs(mean(&blocks)) - 20.0;
+        let mut loudness: Vec<f64> = blocks
+            .into_iter()
+            .map(power_to_lufs)
+            .filter(|l| *l > relative_gate)
+            .collect();
+        loudness.sort_by(f64::total_cmp);
+        let percentile = |p: f64| loudness[((loudness.len() - 1) as f64 * p).round() as usize];
+        (percentile(0.95) - percentile(0.1)) as Float
+    }
+
+    /// Largest absolute sample value, in dBFS.
+    pub fn sample_peak(&self) -> Float {
+        super::gain_to_db(self.peak())
+    }
+
+    /// Largest absolute value of the signal between samples too, in dBTP, estimated by
+    /// oversampling 4 times.
+    pub fn true_peak(&self) -> Float {
+        const PHASES: usize = 4;
+        // taps on each side of the interpolated point
+        const HALF: isize = 12;
+        let sinc = |x: f64| {
+            if x == 0.0 {
+                1.0
+            } else {
+                let x = x * PI;
+                x.sin() / x
+            }
+        };
+        let window = |x: f64| 0.5 * (1.0 + (PI * x / (HALF as f64 + 1.0)).cos());
+        let filters: Vec<Vec<f64>> = (1..PHASES)
+            .map(|phase| {
+                let fraction = phase as f64 / PHASES as f64;
+                (1 - HALF..=HALF)
+                    .map(|j| sinc(fraction - j as f64) * window(fraction - j as f64))
+                    .collect()
+            })
+            .collect();
+
+        let sample = |i: isize| {
+            usize::try_from(i)
+                .ok()
+                .and_then(|i| self.wave.get(i))
+                .map_or(0.0, |x| to_f64(*x))
+        };
+        let mut peak = to_f64(self.peak());
+        for n in 0..self.wave.len() as isize {
+            for filter in &filters {
+                let value: f64 = (1 - HALF..=HALF)
+                    .zip(filter)
+                    .map(|(j, h)| sample(n + j) * h)
+                    .sum();
+                peak = peak.max(value.abs());
+            }
+        }
+        super::gain_to_db(peak as Float)
+    }
+
+    /// Scales the wave so its integrated loudness is `target` LUFS. Silence is left untouched.
+    pub fn normalize_loudness(&mut self, target: Float) {
+        let loudness = self.integrated_loudness();
+        if loudness.is_finite() {
+            self.apply_gain(db_to_gain(target - loudness));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+
+    /// A 1 kHz sine whose peak is at `level` dBFS. Levels are 3 dB above those of the stereo
+    /// signals of EBU Tech 3341, since a single channel is 3 dB quieter than two.
+    fn sine(level: f64, seconds: f64) -> Vec<Float> {
+        let amplitude = 10f64.powf(level / 20.0);
+        let length = (seconds * SAMPLERATE as f64).round() as usize;
+        (0..length)
+            .map(|i| {
+                let t = i as f64 / SAMPLERATE as f64;
+                (amplitude * (2.0 * PI * 1000.0 * t).sin()) as Float
+            })
+            .collect()
+    }
+
+    /// A wave made of sines at each level for each duration, one after the other.
+    fn steps(levels: &[(f64, f64)]) -> AudioWave {
+        let samples = levels.iter().flat_map(|(l, s)| sine(*l, *s)).collect();
+        AudioWave::from_samples(samples, SAMPLERATE)
+    }
+
+    fn assert_close(actual: Float, expected: f64, tolerance: f64) {
+        assert!(
+            (to_f64(actual) - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn sine_at_minus_20_dbfs_reads_minus_23_lufs() {
+        let wave = steps(&[(-20.0, 20.0)]);
+        assert_close(wave.integrated_loudness(), -23.0, 0.1);
+        for loudness in wave.momentary_loudness() {
+            assert_close(loudness, -23.0, 0.1);
+        }
+        for loudness in wave.short_term_loudness() {
+            assert_close(loudness, -23.0, 0.1);
+        }
+        assert_close(wave.sample_peak(), -20.0, 0.01);
+    }
+
+    #[test]
+    fn quiet_passages_are_gated_out() {
+        // EBU Tech 3341, test 3: the relative gate leaves out the quieter sines
+        let wave = steps(&[(-33.0, 10.0), (-20.0, 60.0), (-33.0, 10.0)]);
+        assert_close(wave.integrated_loudness(), -23.0, 0.1);
+        // test 4: the absolute gate leaves out what is below -70 LUFS
+        let wave = steps(&[
+            (-69.0, 10.0),
+            (-33.0, 10.0),
+            (-20.0, 60.0),
+            (-33.0, 10.0),
+            (-69.0, 10.0),
+        ]);
+        assert_close(wave.integrated_loudness(), -23.0, 0.1);
+        assert_close(steps(&[(-60.0, 5.0)]).integrated_loudness(), -63.0, 0.1);
+        assert_eq!(
+            steps(&[(-69.0, 5.0)]).integrated_loudness(),
+            Float::NEG_INFINITY
+        );
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let wave = AudioWave::from_samples(vec![0.0; SAMPLERATE as usize], SAMPLERATE);
+        assert_eq!(wave.integrated_loudness(), Float::NEG_INFINITY);
+        assert_eq!(wave.loudness_range(), 0.0);
+        let short = steps(&[(-20.0, 0.3)]);
+        assert_eq!(short.integrated_loudness(), Float::NEG_INFINITY);
+    }
+
+    #[test]
+    fn loudness_range_spans_the_steps() {
+        // EBU Tech 3342, test 1: 20 s at -20 LUFS then 20 s at -30 LUFS
+        let wave = steps(&[(-17.0, 20.0), (-27.0, 20.0)]);
+        assert_close(wave.loudness_range(), 10.0, 1.0);
+    }
+
+    #[test]
+    fn normalizing_reaches_the_target_loudness() {
+        let mut wave = steps(&[(-20.0, 5.0), (-30.0, 5.0)]);
+        wave.normalize_loudness(-16.0);
+        assert_close(wave.integrated_loudness(), -16.0, 0.01);
+    }
+
+    #[test]
+    fn true_peaks_can_fall_between_samples() {
+        // a sine at a quarter of the sample rate, with its peaks halfway between samples
+        let samples = (0..SAMPLERATE)
+            .map(|n| (PI / 2.0 * n as f64 + PI / 4.0).sin() as Float)
+            .collect();
+        let wave = AudioWave::from_samples(samples, SAMPLERATE);
+        assert_close(wave.sample_peak(), -3.01, 0.01);
+        assert_close(wave.true_peak(), 0.0, 0.2);
+        // where the samples are the peaks, both agree
+        let peaks = steps(&[(-6.0, 1.0)]);
+        assert_close(peaks.true_peak(), to_f64(peaks.sample_peak()), 0.01);
+    }
+}
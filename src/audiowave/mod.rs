@@ -1,12 +1,17 @@
+mod dynamics;
+mod filter;
+mod loudness;
+mod smpl;
 mod utils;
 
-use crate::definitions::{Float, PI};
+use crate::definitions::{Float, PI, SAMPLERATE};
 use crate::function::Function;
-use hound;
+pub use dynamics::{Compressor, Gate, Limiter, SoftClip};
+pub use filter::{Equalizer, Filter, FilterKind};
+pub use smpl::{read_sampler_info, SampleLoop};
 use std::error::Error;
 use std::fmt::Display;
-use std::i16;
-use utils::{clip_value, scale_wave};
+pub use utils::{db_to_gain, gain_to_db};
 
 #[derive(Debug)]
 pub enum WavImportError {
@@ -25,9 +30,17 @@ impl Display for WavImportError {
 
 impl Error for WavImportError {}
 
+impl From<hound::Error> for WavImportError {
+    fn from(e: hound::Error) -> Self {
+        match e {
+            hound::Error::IoError(e) => WavImportError::IOErr(e),
+            e => WavImportError::ParseError(e.to_string()),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AudioWave {
-    significance: Float,
     samplerate: u32,
     duration: Float, // TODO: maybe use `std::time::Duration`?
     pub wave: Vec<Float>,
@@ -41,84 +54,119 @@ impl AudioWave {
         latency: Option<Float>,
         samplerate: Option<u32>,
         waveform: Option<Function>,
-        yclip: Option<Float>,
     ) -> Option<AudioWave> {
-        let latency: Float = latency.unwrap_or(0.0);
-        let samplerate: u32 = samplerate.unwrap_or(44100);
-        let yclip: Float = yclip.unwrap_or(1.0);
+        let samplerate: u32 = samplerate.unwrap_or(SAMPLERATE);
+        let latency = (latency.unwrap_or(0.0).abs() * samplerate as Float).round() as usize;
+        let samples = (duration.abs() * samplerate as Float).round() as usize;
+        let mut result = AudioWave::with_samples(freq, amp, samples, Some(samplerate), waveform)?;
+        result.wave.splice(0..0, std::iter::repeat_n(0.0, latency));
+        result.duration = duration.abs();
+        Some(result)
+    }
+
+    /// Same as `new`, lasting exactly `samples` samples. The time given to `freq` and `amp`
+    /// is computed from the index of each sample, so it doesn't drift on long sounds.
+    pub fn with_samples(
+        freq: &Function,
+        amp: &Function,
+        samples: usize,
+        samplerate: Option<u32>,
+        waveform: Option<Function>,
+    ) -> Option<AudioWave> {
+        let samplerate: u32 = samplerate.unwrap_or(SAMPLERATE);
         let waveform: Function = waveform.unwrap_or(Function::Function(Box::new(|t: Float| {
             (2.0 * PI * t).sin()
         })));
 
-        let f_samplerate: Float = samplerate as Float;
-        let computed_capacity: Float = f_samplerate * duration;
-        let veccapacity: usize = computed_capacity.ceil() as usize;
-        let mut wave: Vec<Float> = Vec::with_capacity(veccapacity);
-
-        let duration: Float = duration.abs();
-        let latency: Float = latency.abs();
-        let yclip: Float = yclip.abs();
-
-        let significance: Float = 1.0;
-
+        let mut wave: Vec<Float> = Vec::with_capacity(samples);
         let mut y: Float = 0.0;
-        let mut t: Float = 0.0;
         let dt: Float = 1.0 / samplerate as Float;
-
-        if latency > 0.0 {
-            t = -latency;
-            while t < 0.0 {
-                wave.push(0.0);
-                t += dt;
-            }
-        }
-        while t < duration {
+        for n in 0..samples {
+            let t = (n as f64 / samplerate as f64) as Float;
             y += freq.get(t) * dt;
-            wave.push(clip_value(waveform.get(y) * amp.get(t), yclip));
-            t += dt;
+            wave.push(waveform.get(y) * amp.get(t));
         }
         Some(AudioWave {
-            significance,
             samplerate,
-            duration,
+            duration: samples as Float / samplerate as Float,
             wave,
         })
     }
 
-    pub fn add(self, other: AudioWave) -> Option<AudioWave> {
+    /// A wave made of the given samples.
+    pub fn from_samples(wave: Vec<Float>, samplerate: u32) -> AudioWave {
+        AudioWave {
+            samplerate,
+            duration: wave.len() as Float / samplerate as Float,
+            wave,
+        }
+    }
+
+    /// Sums `other` into this wave, growing it in place if `other` is longer.
+    pub fn add(mut self, other: AudioWave) -> Option<AudioWave> {
         if self.samplerate != other.samplerate {
             return None;
         }
-        let significance: Float = self.significance + other.significance;
-        let wave: Vec<Float> = utils::sum_waves(self.wave, other.wave);
-        let duration: Float = self.duration.max(other.duration);
-        let samplerate = self.samplerate;
-        Some(AudioWave {
-            significance,
-            samplerate,
-            duration,
-            wave,
-        })
+        utils::add_into(&mut self.wave, &other.wave);
+        self.duration = self.duration.max(other.duration);
+        Some(self)
     }
 
-    pub fn append(self, other: AudioWave, new_significance: Option<Float>) -> Option<AudioWave> {
+    /// Plays `other` after this wave. Samples are kept as they are.
+    pub fn append(mut self, other: AudioWave) -> Option<AudioWave> {
         if self.samplerate != other.samplerate {
             return None;
         }
+        self.wave.extend(other.wave);
+        self.duration += other.duration;
+        Some(self)
+    }
 
-        let new_significance: Float = new_significance.unwrap_or(1.0);
+    /// Adds `other` into this wave starting `start` seconds in, growing this wave if needed.
+    pub fn mix_at(&mut self, other: &AudioWave, start: Float) -> Option<()> {
+        let offset = (start.max(0.0) * self.samplerate as Float).round() as usize;
+        self.mix_at_sample(other, offset)
+    }
 
-        let mut first_wave: Vec<Float> =
-            scale_wave(self.wave, new_significance / self.significance);
-        let second_wave: Vec<Float> = scale_wave(other.wave, new_significance / other.significance);
-        first_wave.extend(second_wave.into_iter());
+    /// Same as `mix_at`, with the start given as a number of samples.
+    pub fn mix_at_sample(&mut self, other: &AudioWave, offset: usize) -> Option<()> {
+        if self.samplerate != other.samplerate {
+            return None;
+        }
+        utils::add_scaled_into(&mut self.wave, offset, &other.wave, 1.0);
+        let start = offset as Float / self.samplerate as Float;
+        self.duration = self.duration.max(start + other.duration);
+        Some(())
+    }
 
-        Some(AudioWave {
-            significance: new_significance,
-            samplerate: self.samplerate,
-            duration: self.duration + other.duration,
-            wave: first_wave,
-        })
+    /// Pads the wave with silence so it lasts at least `samples` samples.
+    pub fn extend_to(&mut self, samples: usize) {
+        if self.wave.len() < samples {
+            self.wave.resize(samples, 0.0);
+        }
+        self.duration = self
+            .duration
+            .max(samples as Float / self.samplerate as Float);
+    }
+
+    /// Multiplies every sample by `gain`.
+    pub fn apply_gain(&mut self, gain: Float) {
+        for sample in &mut self.wave {
+            *sample *= gain;
+        }
+    }
+
+    /// Returns the largest absolute sample value.
+    pub fn peak(&self) -> Float {
+        self.wave.iter().fold(0.0, |peak, x| peak.max(x.abs()))
+    }
+
+    /// Scales the wave so its peak is at `target` dBFS. Silence is left untouched.
+    pub fn normalize_peak(&mut self, target: Float) {
+        let peak = self.peak();
+        if peak > 0.0 {
+            self.apply_gain(db_to_gain(target) / peak);
+        }
     }
 
     pub fn get_samplerate(&self) -> u32 {
@@ -137,6 +185,7 @@ impl AudioWave {
         todo!()
     }
 
+    /// Writes the wave as a 16-bit WAV file. Samples beyond full scale are clipped.
     pub fn export_wav(self, path: &std::path::Path) -> Result<(), hound::Error> {
         let spec = hound::WavSpec {
             channels: 1,
@@ -144,11 +193,13 @@ impl AudioWave {
             bits_per_sample: 16,
             sample_format: hound::SampleFormat::Int,
         };
-        let mut writer = hound::WavWriter::create("sine.wav", spec)?;
+        let mut writer = hound::WavWriter::create(path, spec)?;
 
-        for sample in self.wave.into_iter().map(|x| {
-            (x / self.significance) * (i16::MAX as Float) // Normalize by significance then scale to i16 range
-        }) {
+        for sample in self
+            .wave
+            .into_iter()
+            .map(|x| x.clamp(-1.0, 1.0) * (i16::MAX as Float))
+        {
             writer
                 .write_sample(sample as i16)
                 .expect("should be able to write i16 to 16 bit wav")
@@ -158,7 +209,29 @@ impl AudioWave {
         Ok(())
     }
 
+    /// Reads a WAV file, mixing its channels down to one. Integer samples are scaled so full
+    /// scale is 1.
     pub fn from_wav(path: &std::path::Path) -> Result<Self, WavImportError> {
-        todo!()
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples: Vec<Float> = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .into_samples::<f32>()
+                .map(|s| s.map(|x| x as Float))
+                .collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let full_scale = (2.0 as Float).powi(spec.bits_per_sample as i32 - 1);
+                reader
+                    .into_samples::<i32>()
+                    .map(|s| s.map(|x| x as Float / full_scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let channels = spec.channels.max(1) as usize;
+        let wave = samples
+            .chunks(channels)
+            .map(|frame| frame.iter().sum::<Float>() / channels as Float)
+            .collect();
+        Ok(AudioWave::from_samples(wave, spec.sample_rate))
     }
 }
@@ -0,0 +1,157 @@
+//! Reading the `smpl` chunk of WAV files, where samplers store the root note and the loop
+//! points of a recording. `hound` skips it, so the RIFF chunks are walked by hand.
+
+use super::WavImportError;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// A loop of a `smpl` chunk, in samples. Unlike in the file, the end is excluded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleLoop {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// What samplers need from a `smpl` chunk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplerInfo {
+    /// MIDI note played by the recording at its own pitch, 60 being middle C.
+    pub unity_note: u32,
+    pub loops: Vec<SampleLoop>,
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_id(reader: &mut impl Read) -> std::io::Result<[u8; 4]> {
+    let mut id = [0; 4];
+    reader.read_exact(&mut id)?;
+    Ok(id)
+}
+
+/// Reads the `smpl` chunk of a WAV file, if it has one.
+pub fn read_sampler_info(path: &Path) -> Result<Option<SamplerInfo>, WavImportError> {
+    let file = File::open(path).map_err(WavImportError::IOErr)?;
+    read_chunks(&mut BufReader::new(file))
+}
+
+/// Walks the chunks of a WAV file until its `smpl` chunk.
+fn read_chunks(reader: &mut (impl Read + Seek)) -> Result<Option<SamplerInfo>, WavImportError> {
+    let parse_error = |e: std::io::Error| WavImportError::ParseError(e.to_string());
+
+    let riff = read_id(reader).map_err(parse_error)?;
+    read_u32(reader).map_err(parse_error)?;
+    let wave = read_id(reader).map_err(parse_error)?;
+    if &riff != b"RIFF" || &wave != b"WAVE" {
+        return Err(WavImportError::ParseError("not a WAV file".to_owned()));
+    }
+    loop {
+        let Ok(id) = read_id(reader) else {
+            // no chunk left
+            return Ok(None);
+        };
+        let size = read_u32(reader).map_err(parse_error)?;
+        if &id != b"smpl" {
+            // chunks are padded to an even size
+            let skip = size as i64 + (size % 2) as i64;
+            reader.seek(SeekFrom::Current(skip)).map_err(parse_error)?;
+            continue;
+        }
+        // manufacturer, product and sample period come before the note
+        let mut header = [0; 9];
+        for value in &mut header {
+            *value = read_u32(reader).map_err(parse_error)?;
+        }
+        let unity_note = header[3];
+        let count = header[7];
+        let mut loops = Vec::new();
+        for _ in 0..count {
+            // identifier, type, start, end, fraction and play count
+            let mut values = [0; 6];
+            for value in &mut values {
+                *value = read_u32(reader).map_err(parse_error)?;
+            }
+            loops.push(SampleLoop {
+                start: values[2] as usize,
+                end: values[3] as usize + 1,
+            });
+        }
+        return Ok(Some(SamplerInfo { unity_note, loops }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend((body.len() as u32).to_le_bytes());
+        bytes.extend(body);
+        if body.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    /// A mono 16-bit WAV file made of the given chunks after its format.
+    fn wav(chunks: &[Vec<u8>]) -> Vec<u8> {
+        // PCM and 1 channel, 44.1 kHz, 88.2 kB/s, and 2 bytes per frame of 16 bits
+        let format = words(&[0x0001_0001, 44100, 88200, 0x0010_0002]);
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &format));
+        body.extend(chunks.concat());
+        chunk(b"RIFF", &body)
+    }
+
+    fn read(bytes: Vec<u8>) -> Result<Option<SamplerInfo>, WavImportError> {
+        read_chunks(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn note_and_loops_are_read() {
+        let sampler = words(&[
+            0, 0, 22675, 57, 0, 0, 0, 2, 0, 1, 0, 100, 199, 0, 0, 2, 0, 0, 49, 0, 0,
+        ]);
+        let bytes = wav(&[
+            chunk(b"data", &[0; 400]),
+            // padded to an even size
+            chunk(b"junk", &[1, 2, 3]),
+            chunk(b"smpl", &sampler),
+        ]);
+        let info = read(bytes).unwrap().unwrap();
+        assert_eq!(info.unity_note, 57);
+        let loops = [
+            SampleLoop {
+                start: 100,
+                end: 200,
+            },
+            SampleLoop { start: 0, end: 50 },
+        ];
+        assert_eq!(info.loops, loops);
+    }
+
+    #[test]
+    fn files_without_the_chunk_have_no_info() {
+        assert_eq!(read(wav(&[chunk(b"data", &[0; 400])])).unwrap(), None);
+    }
+
+    #[test]
+    fn broken_files_are_rejected() {
+        let mut not_riff = wav(&[chunk(b"data", &[0; 4])]);
+        not_riff[..4].copy_from_slice(b"RIFX");
+        assert!(read(not_riff).is_err());
+        // a chunk announcing a loop it doesn't hold
+        let truncated = chunk(b"smpl", &words(&[0, 0, 22675, 60, 0, 0, 0, 1, 0]));
+        assert!(read(wav(&[truncated])).is_err());
+    }
+}
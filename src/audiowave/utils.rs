@@ -1,32 +1,28 @@
-use crate::{definitions::Float, function::Function};
+use crate::definitions::Float;
 
-pub fn sum_waves(this: Vec<Float>, other: Vec<Float>) -> Vec<Float> {
-    let identity = 0.0;
-    let max_len = this.len().max(other.len());
-    let mut result = Vec::with_capacity(max_len);
+/// Adds `other` to `this` sample by sample, growing `this` with silence if it is shorter.
+pub fn add_into(this: &mut Vec<Float>, other: &[Float]) {
+    add_scaled_into(this, 0, other, 1.0);
+}
 
-    for i in 0..max_len {
-        let val1 = this.get(i).unwrap_or(&identity);
-        let val2 = other.get(i).unwrap_or(&identity);
-        result.push(*val1 + *val2);
+/// Adds `other` multiplied by `c` to `this`, starting at sample `offset`.
+/// `this` grows with silence if it is too short.
+pub fn add_scaled_into(this: &mut Vec<Float>, offset: usize, other: &[Float], c: Float) {
+    let end = offset + other.len();
+    if this.len() < end {
+        this.resize(end, 0.0);
+    }
+    for (sample, x) in this[offset..end].iter_mut().zip(other) {
+        *sample += x * c;
     }
-
-    result
 }
 
-/// Scales the wave vector with a significance factor.
-pub fn scale_wave(this: Vec<Float>, c: Float) -> Vec<Float> {
-    this.into_iter().map(|x| x * c).collect()
+/// Converts a level in decibels to the factor samples are multiplied by.
+pub fn db_to_gain(db: Float) -> Float {
+    (10.0 as Float).powf(db / 20.0)
 }
 
-pub fn clip_value(x: Float, upper_lower_boundary: Float) -> Float {
-    let upper_lower_boundary = upper_lower_boundary.abs();
-
-    if x > upper_lower_boundary {
-        upper_lower_boundary
-    } else if x < -upper_lower_boundary {
-        -upper_lower_boundary
-    } else {
-        x
-    }
+/// Converts the factor samples are multiplied by to a level in decibels.
+pub fn gain_to_db(gain: Float) -> Float {
+    20.0 * gain.log10()
 }
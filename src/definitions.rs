@@ -1,4 +1,21 @@
-use core::f32;
-
+/// Precision of samples and of every computation on them. Building with the `f64` feature
+/// switches to double precision, for long pieces where single precision drifts audibly.
+#[cfg(not(feature = "f64"))]
 pub type Float = f32;
-pub const PI: Float = f32::consts::PI;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+#[cfg(not(feature = "f64"))]
+pub const PI: Float = std::f32::consts::PI;
+#[cfg(feature = "f64")]
+pub const PI: Float = std::f64::consts::PI;
+
+/// Sample rate used when none is given, in samples per second.
+pub const SAMPLERATE: u32 = 44100;
+
+/// Converts a `Float` to `f64`, for the few computations, like keeping time over a whole piece,
+/// that need double precision whatever the precision of samples.
+#[allow(clippy::unnecessary_cast)]
+pub fn to_f64(x: Float) -> f64 {
+    x as f64
+}
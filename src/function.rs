@@ -1,8 +1,10 @@
 use crate::definitions::Float;
 
+/// A value changing over time. Functions can be shared between threads, so the sounds using
+/// them can be rendered in parallel.
 pub enum Function {
     Const(Float),
-    Function(Box<dyn Fn(Float) -> Float>),
+    Function(Box<dyn Fn(Float) -> Float + Send + Sync>),
 }
 
 impl Function {
@@ -14,12 +16,64 @@ impl Function {
     }
 }
 
-pub fn turn_wave_to_fn(wave: Vec<Float>, samplerate: Option<u32>) -> Function {
-    let samplerate = samplerate.unwrap_or(44100);
-    let seclen = (wave.len() as Float) / (samplerate as Float);
-    Function::Function(Box::new(move |t: Float| -> Float {
-        let mut t = ((t % seclen) + seclen) % seclen;
-        t *= samplerate as Float;
-        wave[t as usize]
-    }))
+/// How a value is read between two samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    /// The sample before.
+    Nearest,
+    /// A straight line between the two samples around.
+    Linear,
+    /// A Catmull-Rom curve through the four samples around, smoother than a line.
+    Cubic,
+}
+
+impl Interpolation {
+    pub fn from_name(name: &str) -> Option<Interpolation> {
+        match name {
+            "nearest" => Some(Interpolation::Nearest),
+            "linear" => Some(Interpolation::Linear),
+            "cubic" => Some(Interpolation::Cubic),
+            _ => None,
+        }
+    }
+
+    /// Value of `wave` at `position`, in samples, going back to the start after the end.
+    pub fn looped(&self, wave: &[Float], position: Float) -> Float {
+        let len = wave.len();
+        if len == 0 {
+            return 0.0;
+        }
+        let position = position.rem_euclid(len as Float);
+        let i = (position as usize).min(len - 1);
+        self.interpolate(position - i as Float, |offset| {
+            wave[(i + len).wrapping_add_signed(offset) % len]
+        })
+    }
+
+    /// Value of `wave` a fraction `x` of a sample after sample `i`, the samples before the
+    /// start and after the end being taken as equal to the first and last ones.
+    pub fn at(&self, wave: &[Float], i: usize, x: Float) -> Float {
+        if wave.is_empty() {
+            return 0.0;
+        }
+        self.interpolate(x, |offset| {
+            wave[i.saturating_add_signed(offset).min(wave.len() - 1)]
+        })
+    }
+
+    /// Interpolates a fraction `x` of a sample after the sample at offset 0, given a way to
+    /// read the samples around.
+    fn interpolate(&self, x: Float, sample: impl Fn(isize) -> Float) -> Float {
+        match self {
+            Interpolation::Nearest => sample(0),
+            Interpolation::Linear => sample(0) + (sample(1) - sample(0)) * x,
+            Interpolation::Cubic => {
+                let (y0, y1, y2, y3) = (sample(-1), sample(0), sample(1), sample(2));
+                let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+                let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c = 0.5 * (y2 - y0);
+                ((a * x + b) * x + c) * x + y1
+            }
+        }
+    }
 }
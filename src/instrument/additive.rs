@@ -0,0 +1,158 @@
+use super::Envelope;
+use crate::audiowave::AudioWave;
+use crate::definitions::{to_f64, Float};
+use crate::function::Function;
+use std::f64::consts::PI;
+
+/// A sine at a multiple of the frequency of the note.
+#[derive(Clone, Copy, Debug)]
+pub struct Partial {
+    pub amplitude: Float,
+    /// Phase at the start of the note, in cycles.
+    pub phase: Float,
+    pub envelope: Envelope,
+}
+
+/// A sum of partials, the n-th one being at n times the frequency of the note, or a bit
+/// above with some `stretch`.
+#[derive(Clone, Debug)]
+pub struct Additive {
+    pub partials: Vec<Partial>,
+    /// Inharmonicity coefficient: the n-th partial is at `n * sqrt(1 + stretch * n²)` times
+    /// the frequency of the note, like the strings of a piano.
+    pub stretch: Float,
+}
+
+impl Additive {
+    /// Renders a note held for `samples` samples, followed by the longest release of the
+    /// partials. Amplitudes are relative to each other: the partials are scaled so their
+    /// amplitudes add up to 1. Partials above the Nyquist frequency are left out.
+    pub fn render(
+        &self,
+        freq: &Function,
+        amp: &Function,
+        samples: usize,
+        samplerate: u32,
+    ) -> Option<AudioWave> {
+        let rate = samplerate as Float;
+        let held = samples as Float / rate;
+        let release = self
+            .partials
+            .iter()
+            .map(|p| p.envelope.release)
+            .fold(0.0, Float::max);
+        let length = samples + (release * rate).round() as usize;
+        let total: Float = self.partials.iter().map(|p| p.amplitude.abs()).sum();
+        let ratios: Vec<f64> = (1..=self.partials.len())
+            .map(|n| {
+                let n = n as f64;
+                n * (1.0 + to_f64(self.stretch) * n * n).sqrt()
+            })
+            .collect();
+        let nyquist = samplerate as f64 / 2.0;
+
+        let mut phases: Vec<f64> = self.partials.iter().map(|p| to_f64(p.phase)).collect();
+        let mut wave = Vec::with_capacity(length);
+        let dt = 1.0 / samplerate as f64;
+        for n in 0..length {
+            let t = (n as f64 / samplerate as f64) as Float;
+            let f = to_f64(freq.get(t));
+            let mut sample = 0.0;
+            for ((partial, ratio), phase) in self.partials.iter().zip(&ratios).zip(&mut phases) {
+                *phase += f * ratio * dt;
+                if f * ratio < nyquist {
+                    let level = to_f64(partial.amplitude * partial.envelope.level(t, held));
+                    sample += level * (2.0 * PI * *phase).sin();
+                }
+            }
+            wave.push(sample as Float / total.max(Float::MIN_POSITIVE) * amp.get(t));
+        }
+        Some(AudioWave::from_samples(wave, samplerate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+    use crate::instrument::tests::{fundamental, play};
+    use crate::instrument::Instrument;
+
+    fn additive(amplitudes: &[Float], release: Float) -> Additive {
+        let envelope = Envelope {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release,
+        };
+        Additive {
+            partials: amplitudes
+                .iter()
+                .map(|amplitude| Partial {
+                    amplitude: *amplitude,
+                    phase: 0.0,
+                    envelope,
+                })
+                .collect(),
+            stretch: 0.0,
+        }
+    }
+
+    /// A sine at `freq` Hz starting `phase` cycles in, moving forward before each sample like
+    /// oscillators do.
+    fn sine(freq: f64, phase: f64, n: usize) -> f64 {
+        (2.0 * PI * (phase + freq * (n + 1) as f64 / SAMPLERATE as f64)).sin()
+    }
+
+    #[test]
+    fn a_single_harmonic_is_a_sine() {
+        let mut synth = additive(&[0.3], 0.0);
+        let wave = play(&Instrument::Additive(synth.clone()), 440.0, 0.2);
+        for (n, x) in wave.into_iter().enumerate() {
+            assert!((to_f64(x) - sine(440.0, 0.0, n)).abs() < 1e-5);
+        }
+        synth.partials[0].phase = 0.25;
+        let wave = play(&Instrument::Additive(synth), 440.0, 0.2);
+        for (n, x) in wave.into_iter().enumerate() {
+            assert!((to_f64(x) - sine(440.0, 0.25, n)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn notes_last_for_the_longest_release() {
+        let mut synth = additive(&[1.0, 0.5, 0.25], 0.1);
+        synth.partials[2].envelope.release = 0.3;
+        let wave = play(&Instrument::Additive(synth), 220.0, 0.5);
+        let rate = SAMPLERATE as usize;
+        assert_eq!(wave.len(), rate / 2 + rate * 3 / 10);
+        // only the third harmonic is left after 100 ms of release, fading out
+        let late = &wave[rate / 2 + rate / 5..];
+        assert!(late.iter().all(|x| x.abs() <= 0.25 / 1.75 * 0.5 + 1e-4));
+        assert!(wave.last().unwrap().abs() < 1e-3);
+    }
+
+    #[test]
+    fn harmonics_are_in_tune_and_add_up_to_1() {
+        let synth = Instrument::Additive(additive(&[1.0, 0.5, 0.33, 0.25, 0.2], 0.0));
+        for freq in [110.0, 440.0] {
+            let wave = play(&synth, freq, 0.2);
+            let measured = fundamental(&wave);
+            assert!((measured / freq - 1.0).abs() < 0.002, "{} Hz", measured);
+            assert!(wave.iter().all(|x| x.abs() <= 1.0));
+        }
+    }
+
+    #[test]
+    fn harmonics_above_nyquist_are_left_out() {
+        let all = Instrument::Additive(additive(&[1.0, 1.0, 1.0, 1.0], 0.0));
+        let audible = Instrument::Additive(additive(&[1.0, 1.0], 0.0));
+        // at 8 kHz, only the first two harmonics are below 22.05 kHz
+        let scaled: Vec<Float> = play(&audible, 8000.0, 0.1)
+            .iter()
+            .map(|x| x / 2.0)
+            .collect();
+        for (a, b) in play(&all, 8000.0, 0.1).iter().zip(scaled) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+}
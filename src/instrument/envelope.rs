@@ -0,0 +1,47 @@
+use crate::definitions::Float;
+
+/// An ADSR envelope: the level rises from 0 to 1 in `attack`, falls to `sustain` in `decay`,
+/// stays there while the note is held, and goes back to 0 in `release` once it is let go.
+/// Times are in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Envelope {
+    pub attack: Float,
+    pub decay: Float,
+    pub sustain: Float,
+    pub release: Float,
+}
+
+impl Default for Envelope {
+    /// Fades short enough to sound like the note itself, but avoiding clicks.
+    fn default() -> Self {
+        Envelope {
+            attack: 0.005,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.02,
+        }
+    }
+}
+
+impl Envelope {
+    /// Level `t` seconds after the start of a note held for `held` seconds.
+    pub fn level(&self, t: Float, held: Float) -> Float {
+        if t < held {
+            self.held_level(t)
+        } else if t - held < self.release {
+            self.held_level(held) * (1.0 - (t - held) / self.release)
+        } else {
+            0.0
+        }
+    }
+
+    fn held_level(&self, t: Float) -> Float {
+        if t < self.attack {
+            t / self.attack
+        } else if t - self.attack < self.decay {
+            1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use super::Envelope;
+use crate::audiowave::AudioWave;
+use crate::definitions::{to_f64, Float};
+use crate::function::Function;
+use std::f64::consts::PI;
+
+/// How the operators of an FM instrument are connected. Operators are numbered from 1, and
+/// only ever modulate operators with a lower number.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// Each operator modulates the one before it, and only the first one is heard.
+    Stack,
+    /// Every operator modulates the first one, which is the only one heard.
+    Branch,
+    /// Operators go in pairs, the second of each pair modulating the first, which is heard.
+    Pairs,
+    /// Every operator is heard and none is modulated, like an additive organ.
+    Parallel,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "stack" => Some(Algorithm::Stack),
+            "branch" => Some(Algorithm::Branch),
+            "pairs" => Some(Algorithm::Pairs),
+            "parallel" => Some(Algorithm::Parallel),
+            _ => None,
+        }
+    }
+
+    /// Index of the operator modulated by operator `i`, or `None` if it is heard.
+    fn target(&self, i: usize) -> Option<usize> {
+        match self {
+            Algorithm::Stack => i.checked_sub(1),
+            Algorithm::Branch => (i > 0).then_some(0),
+            Algorithm::Pairs => (i % 2 == 1).then(|| i - 1),
+            Algorithm::Parallel => None,
+        }
+    }
+}
+
+/// A sine oscillator of an FM instrument.
+#[derive(Clone, Copy, Debug)]
+pub struct Operator {
+    /// Frequency of the operator relative to the note.
+    pub ratio: Float,
+    /// Modulation index in radians for an operator modulating another one, output level for
+    /// an operator that is heard.
+    pub index: Float,
+    pub envelope: Envelope,
+}
+
+/// Sine operators modulating the phase of each other, as connected by `algorithm`.
+#[derive(Clone, Debug)]
+pub struct Fm {
+    pub algorithm: Algorithm,
+    /// Between 2 and 6 operators.
+    pub operators: Vec<Operator>,
+}
+
+impl Fm {
+    /// Renders a note held for `samples` samples, followed by the longest release of the
+    /// operators that are heard.
+    pub fn render(
+        &self,
+        freq: &Function,
+        amp: &Function,
+        samples: usize,
+        samplerate: u32,
+    ) -> Option<AudioWave> {
+        let rate = samplerate as Float;
+        let held = samples as Float / rate;
+        let heard: Vec<usize> = (0..self.operators.len())
+            .filter(|i| self.algorithm.target(*i).is_none())
+            .collect();
+        let release = heard
+            .iter()
+            .map(|i| self.operators[*i].envelope.release)
+            .fold(0.0, Float::max);
+        let length = samples + (release * rate).round() as usize;
+
+        let mut phases = vec![0.0; self.operators.len()];
+        // phase modulation received by each operator during the current sample
+        let mut modulation = vec![0.0; self.operators.len()];
+        let mut wave = Vec::with_capacity(length);
+        let dt = 1.0 / samplerate as f64;
+        for n in 0..length {
+            let t = (n as f64 / samplerate as f64) as Float;
+            let f = to_f64(freq.get(t));
+            modulation.fill(0.0);
+            let mut sample = 0.0;
+            // modulators come after the operators they modulate, so they are computed first
+            for (i, operator) in self.operators.iter().enumerate().rev() {
+                phases[i] += f * to_f64(operator.ratio) * dt;
+                let level = to_f64(operator.index * operator.envelope.level(t, held));
+                let output = level * (2.0 * PI * phases[i] + modulation[i]).sin();
+                match self.algorithm.target(i) {
+                    Some(target) => modulation[target] += output,
+                    None => sample += output,
+                }
+            }
+            wave.push((sample / heard.len() as f64) as Float * amp.get(t));
+        }
+        Some(AudioWave::from_samples(wave, samplerate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+    use crate::instrument::tests::{fundamental, play};
+    use crate::instrument::Instrument;
+
+    fn operator(ratio: Float, index: Float, release: Float) -> Operator {
+        Operator {
+            ratio,
+            index,
+            envelope: Envelope {
+                attack: 0.0,
+                decay: 0.0,
+                sustain: 1.0,
+                release,
+            },
+        }
+    }
+
+    #[test]
+    fn notes_last_for_the_release_of_the_operators_heard() {
+        let fm = |algorithm| {
+            Instrument::Fm(Fm {
+                algorithm,
+                operators: vec![operator(1.0, 1.0, 0.1), operator(2.0, 1.0, 0.5)],
+            })
+        };
+        let rate = SAMPLERATE as usize;
+        // the modulator's longer release only counts when it is heard
+        assert_eq!(
+            play(&fm(Algorithm::Stack), 440.0, 0.5).len(),
+            rate / 2 + rate / 10
+        );
+        assert_eq!(play(&fm(Algorithm::Parallel), 440.0, 0.5).len(), rate);
+        let tail = play(&fm(Algorithm::Stack), 440.0, 0.5);
+        assert!(tail.last().unwrap().abs() < 1e-3);
+    }
+
+    #[test]
+    fn notes_play_at_their_pitch() {
+        for algorithm in [Algorithm::Stack, Algorithm::Branch, Algorithm::Pairs] {
+            let fm = Instrument::Fm(Fm {
+                algorithm,
+                operators: vec![
+                    operator(1.0, 1.0, 0.0),
+                    operator(2.0, 1.5, 0.0),
+                    operator(1.0, 1.0, 0.0),
+                    operator(3.0, 0.5, 0.0),
+                ],
+            });
+            for freq in [110.0, 440.0] {
+                let measured = fundamental(&play(&fm, freq, 0.2));
+                assert!((measured / freq - 1.0).abs() < 0.002, "{} Hz", measured);
+            }
+        }
+    }
+
+    #[test]
+    fn unmodulated_carriers_are_sines() {
+        let fm = Instrument::Fm(Fm {
+            algorithm: Algorithm::Stack,
+            operators: vec![operator(1.0, 1.0, 0.0), operator(3.0, 0.0, 0.0)],
+        });
+        for (n, x) in play(&fm, 440.0, 0.2).into_iter().enumerate() {
+            // phases move forward before each sample, like those of oscillators
+            let sine = (2.0 * PI * 440.0 * (n + 1) as f64 / SAMPLERATE as f64).sin();
+            assert!((to_f64(x) - sine).abs() < 1e-5);
+        }
+    }
+}
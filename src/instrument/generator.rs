@@ -0,0 +1,71 @@
+use super::Envelope;
+use crate::audiowave::AudioWave;
+use crate::definitions::Float;
+use crate::function::Function;
+
+/// A sound made one sample at a time, keeping whatever state it needs from one sample to
+/// the next, unlike waveforms which only see the phase.
+pub trait Generator {
+    /// Next sample of the sound, for a note at `freq` Hz, still `held` or already let go.
+    fn next_sample(&mut self, freq: Float, held: bool) -> Float;
+}
+
+/// Renders a note held for `samples` samples by pulling samples from `generator`, then lets
+/// it ring for `release` seconds while fading out.
+pub fn render(
+    generator: &mut dyn Generator,
+    freq: &Function,
+    amp: &Function,
+    samples: usize,
+    release: Float,
+    samplerate: u32,
+) -> AudioWave {
+    let rate = samplerate as Float;
+    let held = samples as Float / rate;
+    let fade = Envelope {
+        attack: 0.0,
+        decay: 0.0,
+        sustain: 1.0,
+        release,
+    };
+    let length = samples + (release * rate).round() as usize;
+    let wave = (0..length)
+        .map(|n| {
+            let t = (n as f64 / samplerate as f64) as Float;
+            generator.next_sample(freq.get(t), n < samples) * amp.get(t) * fade.level(t, held)
+        })
+        .collect();
+    AudioWave::from_samples(wave, samplerate)
+}
+
+/// A delay line read at fractional delays with linear interpolation.
+pub struct DelayLine {
+    buffer: Vec<f64>,
+    /// Index the next sample is written at.
+    write: usize,
+}
+
+impl DelayLine {
+    /// A delay line holding up to `length` samples.
+    pub fn new(length: usize) -> Self {
+        DelayLine {
+            buffer: vec![0.0; length.max(2) + 2],
+            write: 0,
+        }
+    }
+
+    /// Sample written `delay` samples ago, at least 1 and at most the length of the line.
+    pub fn read(&self, delay: f64) -> f64 {
+        let len = self.buffer.len();
+        let delay = delay.clamp(1.0, (len - 2) as f64);
+        let whole = delay.floor() as usize;
+        let fraction = delay - whole as f64;
+        let at = |d: usize| self.buffer[(self.write + len - d) % len];
+        at(whole) * (1.0 - fraction) + at(whole + 1) * fraction
+    }
+
+    pub fn write(&mut self, x: f64) {
+        self.buffer[self.write] = x;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+}
@@ -0,0 +1,171 @@
+//! Instruments turning the notes of a voice into sound.
+
+mod additive;
+mod envelope;
+mod fm;
+mod generator;
+mod oscillator;
+mod physical;
+mod sampler;
+mod subtractive;
+mod wavetable;
+
+use crate::audiowave::AudioWave;
+use crate::definitions::{Float, SAMPLERATE};
+use crate::function::Function;
+pub use additive::{Additive, Partial};
+pub use envelope::Envelope;
+pub use fm::{Algorithm, Fm, Operator};
+pub use oscillator::Waveform;
+pub use physical::{Flute, ModalBar, PluckedString};
+pub use sampler::{Sampler, Zone};
+pub use subtractive::{FilterModel, Subtractive};
+pub use wavetable::Wavetable;
+
+/// How the notes of a voice sound, set by `waveform`, `harmonics`, `synth` and `sampler`
+/// lines.
+#[derive(Clone, Debug)]
+pub enum Instrument {
+    /// A plain oscillator, starting and stopping with the note.
+    Oscillator(Waveform),
+    Wavetable(Wavetable),
+    Subtractive(Subtractive),
+    Fm(Fm),
+    Additive(Additive),
+    Sampler(Sampler),
+    String(PluckedString),
+    Flute(Flute),
+    Bar(ModalBar),
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Instrument::Oscillator(Waveform::Sine)
+    }
+}
+
+impl Instrument {
+    /// Renders a note held for `samples` samples and played with `velocity`, from 0 to 1. The
+    /// wave can be longer when the instrument keeps ringing after the note is let go.
+    pub fn render(
+        &self,
+        freq: &Function,
+        amp: &Function,
+        velocity: Float,
+        samples: usize,
+        samplerate: Option<u32>,
+    ) -> Option<AudioWave> {
+        let samplerate = samplerate.unwrap_or(SAMPLERATE);
+        match self {
+            Instrument::Oscillator(waveform) => AudioWave::with_samples(
+                freq,
+                amp,
+                samples,
+                Some(samplerate),
+                Some(waveform.function()),
+            ),
+            Instrument::Wavetable(table) => table.render(freq, amp, samples, samplerate),
+            Instrument::Subtractive(synth) => synth.render(freq, amp, samples, samplerate),
+            Instrument::Fm(synth) => synth.render(freq, amp, samples, samplerate),
+            Instrument::Additive(synth) => synth.render(freq, amp, samples, samplerate),
+            Instrument::Sampler(sampler) => {
+                sampler.render(freq, amp, velocity, samples, samplerate)
+            }
+            Instrument::String(model) => Some(generator::render(
+                &mut model.generator(freq.get(0.0), samplerate),
+                freq,
+                amp,
+                samples,
+                model.release,
+                samplerate,
+            )),
+            Instrument::Flute(model) => Some(generator::render(
+                &mut model.generator(samplerate),
+                freq,
+                amp,
+                samples,
+                model.release,
+                samplerate,
+            )),
+            Instrument::Bar(model) => Some(generator::render(
+                &mut model.generator(freq.get(0.0), samplerate),
+                freq,
+                amp,
+                samples,
+                model.release,
+                samplerate,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Measurements shared by the tests of the instruments.
+
+    use super::*;
+
+    /// Renders a note held for `seconds` at full velocity.
+    pub fn play(instrument: &Instrument, freq: Float, seconds: Float) -> Vec<Float> {
+        let samples = (seconds * SAMPLERATE as Float).round() as usize;
+        instrument
+            .render(
+                &Function::Const(freq),
+                &Function::Const(1.0),
+                1.0,
+                samples,
+                None,
+            )
+            .unwrap()
+            .wave
+    }
+
+    pub fn rms(wave: &[Float]) -> Float {
+        (wave.iter().map(|x| x * x).sum::<Float>() / wave.len().max(1) as Float).sqrt()
+    }
+
+    /// Frequency of the fundamental of a periodic wave, in Hz: the shortest period whose
+    /// autocorrelation is close to the highest one, down to 20 Hz.
+    pub fn fundamental(wave: &[Float]) -> Float {
+        let longest = SAMPLERATE as usize / 20;
+        let window = &wave[..(wave.len() - longest - 1).min(8192)];
+        let correlation = |lag: usize| {
+            let (mut product, mut energy, mut lagged) = (0.0, 0.0, 0.0);
+            for (x, y) in window.iter().zip(&wave[lag..]) {
+                product += x * y;
+                energy += x * x;
+                lagged += y * y;
+            }
+            product / (energy * lagged).sqrt().max(Float::MIN_POSITIVE)
+        };
+        let correlations: Vec<Float> = (0..=longest + 1).map(correlation).collect();
+        // past the lags so short that the wave hasn't changed yet
+        let start = correlations.iter().position(|c| *c < 0.0).unwrap();
+        let highest = correlations[start..longest]
+            .iter()
+            .fold(Float::MIN, |a, b| a.max(*b));
+        let mut lag = start
+            + correlations[start..]
+                .iter()
+                .position(|c| *c > 0.9 * highest)
+                .unwrap();
+        while correlations[lag + 1] > correlations[lag] {
+            lag += 1;
+        }
+        let (before, peak, after) = (
+            correlations[lag - 1],
+            correlations[lag],
+            correlations[lag + 1],
+        );
+        let shift = 0.5 * (before - after) / (before - 2.0 * peak + after);
+        SAMPLERATE as Float / (lag as Float + shift)
+    }
+
+    #[test]
+    fn fundamentals_of_plain_waveforms() {
+        for waveform in [Waveform::Sine, Waveform::Saw, Waveform::Square] {
+            let wave = play(&Instrument::Oscillator(waveform), 261.63, 0.3);
+            assert!((fundamental(&wave) / 261.63 - 1.0).abs() < 0.002);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::definitions::{Float, PI};
+use crate::function::Function;
+
+/// Shapes of a single cycle, all starting at 0 and going up like a sine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "saw" => Some(Waveform::Saw),
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            _ => None,
+        }
+    }
+
+    /// Value of the wave `phase` cycles in.
+    pub fn at(&self, phase: Float) -> Float {
+        let fraction = phase - phase.floor();
+        match self {
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Saw => {
+                let fraction = fraction + 0.5;
+                2.0 * (fraction - fraction.floor()) - 1.0
+            }
+            Waveform::Square => {
+                if fraction < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => {
+                let fraction = fraction + 0.25;
+                1.0 - 4.0 * (fraction - fraction.floor() - 0.5).abs()
+            }
+        }
+    }
+
+    /// The wave as a function of the phase, as `AudioWave::new` takes it.
+    pub fn function(self) -> Function {
+        Function::Function(Box::new(move |t: Float| self.at(t)))
+    }
+}
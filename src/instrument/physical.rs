@@ -0,0 +1,438 @@
+//! Instruments simulating how strings, tubes and bars vibrate, rather than building the
+//! waveform directly.
+
+use super::generator::{DelayLine, Generator};
+use crate::definitions::{to_f64, Float};
+use crate::random::Random;
+use std::f64::consts::PI;
+
+/// Lowest frequency the delay lines are long enough for, in Hz.
+const LOWEST_FREQUENCY: f64 = 20.0;
+
+/// A plucked string with the Karplus-Strong algorithm: a burst of noise going round a delay
+/// line as long as a period, losing its high harmonics a bit more on each round.
+#[derive(Clone, Copy, Debug)]
+pub struct PluckedString {
+    /// From 0 to 1, how much faster the high harmonics die out than the fundamental.
+    pub damping: Float,
+    /// Where the string is plucked, from 0 at the bridge to 0.5 in the middle. Plucking in
+    /// the middle gives a rounder sound.
+    pub pick: Float,
+    /// Time the fundamental takes to fall by 60 dB, in seconds.
+    pub decay: Float,
+    /// Time the string keeps ringing after the note is let go, in seconds.
+    pub release: Float,
+}
+
+impl Default for PluckedString {
+    fn default() -> Self {
+        PluckedString {
+            damping: 0.5,
+            pick: 0.15,
+            decay: 3.0,
+            release: 0.2,
+        }
+    }
+}
+
+struct StringState {
+    model: PluckedString,
+    samplerate: f64,
+    delay: DelayLine,
+    /// Noise shaped by the pick position, fed into the string during the first period.
+    excitation: Vec<f64>,
+    /// Samples played so far.
+    n: usize,
+    /// Sample read from the delay line the time before, for the loop filter.
+    last: f64,
+}
+
+impl PluckedString {
+    /// Starts a note plucked at `freq` Hz. The string is only plucked at the start of the
+    /// note, so the burst of noise lasts a period at that pitch, but the string then follows
+    /// the frequency of each sample, so glissandos bend it like they do the flute.
+    pub fn generator(&self, freq: Float, samplerate: u32) -> impl Generator {
+        let samplerate = samplerate as f64;
+        let period = (samplerate / to_f64(freq).max(LOWEST_FREQUENCY)).round() as usize;
+        let mut noise = Random::new(0);
+        let burst: Vec<f64> = (0..period).map(|_| noise.next_signed()).collect();
+        // plucking at a fraction of the string removes the harmonics with a node there
+        let pick = (to_f64(self.pick).clamp(0.0, 0.5) * period as f64).round() as usize;
+        let excitation = (0..period)
+            .map(|i| 0.5 * (burst[i] - i.checked_sub(pick).map_or(0.0, |j| burst[j])))
+            .collect();
+        StringState {
+            model: *self,
+            samplerate,
+            delay: DelayLine::new((samplerate / LOWEST_FREQUENCY) as usize),
+            excitation,
+            n: 0,
+            last: 0.0,
+        }
+    }
+}
+
+impl Generator for StringState {
+    fn next_sample(&mut self, freq: Float, _held: bool) -> Float {
+        let freq = to_f64(freq).max(LOWEST_FREQUENCY);
+        // the averaging of the loop filter delays the signal by `b` samples
+        let b = 0.5 * to_f64(self.model.damping).clamp(0.0, 1.0);
+        let loss = 10f64.powf(-3.0 / (to_f64(self.model.decay).max(1e-3) * freq));
+        let read = self.delay.read(self.samplerate / freq - b);
+        let filtered = loss * ((1.0 - b) * read + b * self.last);
+        self.last = read;
+        let y = filtered + self.excitation.get(self.n).copied().unwrap_or(0.0);
+        self.n += 1;
+        self.delay.write(y);
+        y as Float
+    }
+}
+
+/// A flute as a waveguide: the jet of air hits the edge of the mouthpiece, and the bore
+/// sends part of the wave back towards it, keeping it oscillating at the pitch of the bore.
+#[derive(Clone, Copy, Debug)]
+pub struct Flute {
+    /// Pressure of the breath, around 1. Too little and the flute doesn't speak, too much
+    /// and it jumps to higher harmonics.
+    pub breath: Float,
+    /// Amount of noise in the breath.
+    pub noise: Float,
+    /// Depth of the vibrato, as a fraction of the breath pressure.
+    pub vibrato: Float,
+    /// Time the flute keeps sounding after the note is let go, in seconds.
+    pub release: Float,
+}
+
+impl Default for Flute {
+    fn default() -> Self {
+        Flute {
+            breath: 1.0,
+            noise: 0.15,
+            vibrato: 0.05,
+            release: 0.08,
+        }
+    }
+}
+
+/// Time the breath takes to build up or stop, in seconds.
+const BREATH_ATTACK: f64 = 0.03;
+/// Frequency of the vibrato, in Hz.
+const VIBRATO_RATE: f64 = 5.0;
+/// Length of the jet relative to the bore.
+const JET_RATIO: f64 = 0.32;
+/// Highest cutoff of the low-pass filter modelling the losses at the end of the bore, in Hz.
+const BORE_CUTOFF: f64 = 3000.0;
+/// Highest cutoff of that filter relative to the frequency of the note.
+const BORE_CUTOFF_RATIO: f64 = 6.0;
+/// Pole of the filter keeping the bore from drifting away from 0.
+const DC_POLE: f64 = 0.995;
+
+struct FluteState {
+    model: Flute,
+    samplerate: f64,
+    bore: DelayLine,
+    jet: DelayLine,
+    noise: Random,
+    /// Level of the breath, from 0 to 1.
+    pressure: f64,
+    /// Samples played so far.
+    n: usize,
+    /// Last output of the bore filter.
+    filtered: f64,
+    /// Last input and output of the DC blocker.
+    dc: (f64, f64),
+}
+
+impl Flute {
+    pub fn generator(&self, samplerate: u32) -> impl Generator {
+        let samplerate = samplerate as f64;
+        // a period and a half, and what the filters take away or add to it
+        let length = (2.0 * samplerate / LOWEST_FREQUENCY) as usize;
+        FluteState {
+            model: *self,
+            samplerate,
+            bore: DelayLine::new(length),
+            jet: DelayLine::new(length),
+            noise: Random::new(0),
+            pressure: 0.0,
+            n: 0,
+            filtered: 0.0,
+            dc: (0.0, 0.0),
+        }
+    }
+}
+
+impl Generator for FluteState {
+    fn next_sample(&mut self, freq: Float, held: bool) -> Float {
+        let freq = to_f64(freq).max(LOWEST_FREQUENCY);
+        let step = 1.0 / (BREATH_ATTACK * self.samplerate);
+        self.pressure = if held {
+            (self.pressure + step).min(1.0)
+        } else {
+            (self.pressure - step).max(0.0)
+        };
+        let t = self.n as f64 / self.samplerate;
+        self.n += 1;
+        let vibrato = to_f64(self.model.vibrato) * (2.0 * PI * VIBRATO_RATE * t).sin();
+        let noise = to_f64(self.model.noise) * self.noise.next_signed();
+        let breath = to_f64(self.model.breath) * 1.1 * self.pressure * (1.0 + noise + vibrato);
+
+        // low notes lose their high modes faster, or they would jump to one of them
+        let cutoff = (BORE_CUTOFF_RATIO * freq).min(BORE_CUTOFF);
+        let pole = (-2.0 * PI * cutoff / self.samplerate).exp();
+        // the bore reflects the wave upside down, so it resonates at odd multiples of half
+        // its period. Like in STK's flute, it lasts a period and a half and the note is its
+        // second mode. The filter delays the wave and the DC blocker moves it forward, and a
+        // sample more makes up for the jet pulling the pitch up.
+        let w = 2.0 * PI * freq / self.samplerate;
+        let filter_delay = (pole * w.sin()).atan2(1.0 - pole * w.cos()) / w;
+        let dc_lead = ((PI - w) / 2.0 - (DC_POLE * w.sin()).atan2(1.0 - DC_POLE * w.cos())) / w;
+        let bore_delay = 1.5 * self.samplerate / freq - filter_delay + dc_lead + 1.0;
+
+        let bore_out = self.bore.read(bore_delay);
+        self.filtered = (1.0 - pole) * bore_out + pole * self.filtered;
+        let reflected = -self.filtered;
+        let blocked = reflected - self.dc.0 + DC_POLE * self.dc.1;
+        self.dc = (reflected, blocked);
+
+        self.jet.write(breath - 0.5 * blocked);
+        let jet = self.jet.read(JET_RATIO * bore_delay);
+        // the jet is deflected in or out of the mouthpiece, but never more than fully
+        let deflected = (jet * (jet * jet - 1.0)).clamp(-1.0, 1.0);
+        self.bore.write(deflected + 0.5 * blocked);
+        (bore_out * 0.55) as Float
+    }
+}
+
+/// A struck bar like a marimba's: a few resonators at the frequencies of the bending modes
+/// of the bar, excited by the mallet.
+#[derive(Clone, Copy, Debug)]
+pub struct ModalBar {
+    /// From 0 to 1: hard mallets hit for a shorter time, bringing out the higher modes.
+    pub hardness: Float,
+    /// Where the bar is struck, from 0 at one end to 1 at the other.
+    pub position: Float,
+    /// Time the fundamental takes to fall by 60 dB, in seconds. Higher modes die out faster.
+    pub decay: Float,
+    /// Time the bar keeps ringing after the note is let go, in seconds.
+    pub release: Float,
+}
+
+impl Default for ModalBar {
+    fn default() -> Self {
+        ModalBar {
+            hardness: 0.5,
+            position: 0.3,
+            decay: 0.5,
+            release: 0.5,
+        }
+    }
+}
+
+/// Frequencies of the modes of a marimba bar relative to the fundamental, with how fast
+/// each one dies out relative to it.
+const BAR_MODES: [(f64, f64); 3] = [(1.0, 1.0), (3.99, 0.65), (10.65, 0.65)];
+
+struct BarState {
+    samplerate: f64,
+    /// Samples of the mallet hit.
+    mallet: Vec<f64>,
+    /// Gain and time to fall by 60 dB of each mode.
+    modes: Vec<(f64, f64)>,
+    /// Last two outputs of each resonator.
+    state: Vec<(f64, f64)>,
+    /// Samples played so far.
+    n: usize,
+}
+
+impl ModalBar {
+    /// Starts a note struck at `freq` Hz. The bar is only struck at the start of the note,
+    /// so the mallet hit is shaped for that pitch, but the modes then follow the frequency of
+    /// each sample, so glissandos bend them like they do the flute.
+    pub fn generator(&self, freq: Float, samplerate: u32) -> impl Generator {
+        let samplerate = samplerate as f64;
+        let hardness = to_f64(self.hardness).clamp(0.0, 1.0);
+        let w = 2.0 * PI * to_f64(freq).max(LOWEST_FREQUENCY) / samplerate;
+        // a hit lasting more than half a period would mostly cancel out the fundamental
+        let length = ((0.2 + 1.5 * (1.0 - hardness)) / 1000.0 * samplerate).min(PI / w);
+        let length = (length.round() as usize).max(1);
+        let pulse: Vec<f64> = (0..length)
+            .map(|i| 1.0 - (2.0 * PI * (i as f64 + 0.5) / length as f64).cos())
+            .collect();
+        // scaled so the fundamental comes out as loud whatever the length of the hit
+        let (re, im) = pulse
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (i, p)| {
+                let (sin, cos) = (w * i as f64).sin_cos();
+                (re + p * cos, im - p * sin)
+            });
+        let response = (re * re + im * im).sqrt().max(1e-9);
+        // modes are louder or quieter depending on where the bar vibrates most
+        let x = PI * to_f64(self.position).clamp(0.0, 1.0);
+        let shapes = [
+            x.sin(),
+            -0.25 * (0.05 + 3.9 * x).sin(),
+            0.9 * (11.0 * x - 0.05).sin(),
+        ];
+        let total: f64 = shapes.iter().map(|s| s.abs()).sum::<f64>().max(1e-9);
+        let modes = BAR_MODES
+            .iter()
+            .zip(shapes)
+            .map(|((_, decay), shape)| (shape / total, to_f64(self.decay).max(1e-3) * decay))
+            .collect();
+        BarState {
+            samplerate,
+            mallet: pulse.iter().map(|p| p / response).collect(),
+            modes,
+            state: vec![(0.0, 0.0); BAR_MODES.len()],
+            n: 0,
+        }
+    }
+}
+
+impl Generator for BarState {
+    fn next_sample(&mut self, freq: Float, _held: bool) -> Float {
+        let input = self.mallet.get(self.n).copied().unwrap_or(0.0);
+        self.n += 1;
+        let mut y = 0.0;
+        for (((ratio, _), (gain, decay)), (y1, y2)) in
+            BAR_MODES.iter().zip(&self.modes).zip(&mut self.state)
+        {
+            let w = 2.0 * PI * to_f64(freq) * ratio / self.samplerate;
+            if w >= PI {
+                // above Nyquist
+                continue;
+            }
+            let r = 10f64.powf(-3.0 / (decay * self.samplerate));
+            // scaled by sin(w) so every mode rings with the same amplitude
+            let out = w.sin() * input + 2.0 * r * w.cos() * *y1 - r * r * *y2;
+            (*y1, *y2) = (out, *y1);
+            y += gain * out;
+        }
+        y as Float
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+    use crate::instrument::tests::{fundamental, play, rms};
+    use crate::instrument::Instrument;
+
+    const RATE: usize = SAMPLERATE as usize;
+
+    /// Plays a second long note, checking it lasts for the release after it and stays
+    /// within full scale.
+    fn play_checked(instrument: &Instrument, freq: Float, release: Float) -> Vec<Float> {
+        let wave = play(instrument, freq, 1.0);
+        assert_eq!(
+            wave.len(),
+            RATE + (release * SAMPLERATE as Float).round() as usize
+        );
+        assert!(wave.iter().all(|x| x.abs() <= 1.0), "{} Hz clips", freq);
+        assert!(wave.last().unwrap().abs() < 1e-3);
+        wave
+    }
+
+    fn assert_in_tune(wave: &[Float], freq: Float, tolerance: Float) {
+        let measured = fundamental(wave);
+        assert!(
+            (measured / freq - 1.0).abs() < tolerance,
+            "{} Hz played at {} Hz",
+            freq,
+            measured
+        );
+    }
+
+    /// Frequency within 10% of `freq` where the spectrum of the wave peaks. Unlike
+    /// `fundamental`, it isn't thrown off by the inharmonic modes of bars.
+    fn spectral_peak(wave: &[Float], freq: Float) -> Float {
+        let magnitude = |f: f64| {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (n, x) in wave.iter().enumerate() {
+                let (sin, cos) = (2.0 * PI * f * n as f64 / SAMPLERATE as f64).sin_cos();
+                re += to_f64(*x) * cos;
+                im += to_f64(*x) * sin;
+            }
+            re * re + im * im
+        };
+        let candidates = (-200..=200).map(|i| to_f64(freq) * (1.0 + i as f64 / 2000.0));
+        candidates
+            .max_by(|a, b| magnitude(*a).total_cmp(&magnitude(*b)))
+            .unwrap() as Float
+    }
+
+    #[test]
+    fn plucked_strings_ring_in_tune_and_die_out() {
+        let model = PluckedString::default();
+        for freq in [82.41, 261.63, 1318.5] {
+            let wave = play_checked(&Instrument::String(model), freq, model.release);
+            assert_in_tune(&wave[RATE / 10..RATE / 2], freq, 0.002);
+            // 3 s to fall by 60 dB is 18 dB in the 900 ms between these
+            let (start, end) = (rms(&wave[..RATE / 10]), rms(&wave[RATE * 9 / 10..RATE]));
+            assert!(
+                start > 4.0 * end && end > 0.0,
+                "{} Hz: {} then {}",
+                freq,
+                start,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn flutes_sound_their_note_while_blown() {
+        let model = Flute::default();
+        // without the vibrato and the noise moving the pitch around
+        let steady = Flute {
+            noise: 0.0,
+            vibrato: 0.0,
+            ..model
+        };
+        for freq in [110.0, 261.63, 440.0, 880.0, 1760.0] {
+            let wave = play_checked(&Instrument::Flute(steady), freq, steady.release);
+            // notes below the range of a flute, from middle C, are a bit flat
+            let tolerance = if freq < 261.0 { 0.01 } else { 0.005 };
+            assert_in_tune(&wave[RATE / 5..RATE * 7 / 10], freq, tolerance);
+            let wave = play_checked(&Instrument::Flute(model), freq, model.release);
+            let (early, late) = (
+                rms(&wave[RATE / 5..RATE / 2]),
+                rms(&wave[RATE * 7 / 10..RATE]),
+            );
+            assert!(
+                early > 0.1 && (late / early - 1.0).abs() < 0.1,
+                "{} Hz",
+                freq
+            );
+        }
+    }
+
+    #[test]
+    fn struck_bars_ring_in_tune_and_die_out() {
+        let model = ModalBar::default();
+        for freq in [110.0, 523.25, 2093.0] {
+            let wave = play_checked(&Instrument::Bar(model), freq, model.release);
+            let measured = spectral_peak(&wave[..RATE / 2], freq);
+            assert!(
+                (measured / freq - 1.0).abs() < 0.001,
+                "{} Hz at {} Hz",
+                freq,
+                measured
+            );
+            // the fundamental falls by 60 dB in 500 ms, and the other modes faster
+            let (start, end) = (
+                rms(&wave[..RATE / 20]),
+                rms(&wave[RATE / 2..RATE * 11 / 20]),
+            );
+            assert!(
+                start > 500.0 * end && end > 0.0,
+                "{} Hz: {} then {}",
+                freq,
+                start,
+                end
+            );
+        }
+    }
+}
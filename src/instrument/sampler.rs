@@ -0,0 +1,185 @@
+use super::Envelope;
+use crate::audiowave::{AudioWave, SampleLoop};
+use crate::definitions::{to_f64, Float};
+use crate::function::{Function, Interpolation};
+use std::sync::Arc;
+
+/// A recording played for some notes and velocities.
+#[derive(Clone, Debug)]
+pub struct Zone {
+    /// Samples of the recording, shared with the other instruments using the same zone.
+    pub sample: Arc<Vec<Float>>,
+    pub samplerate: u32,
+    /// Frequency the recording plays at, in Hz.
+    pub root: Float,
+    /// Lowest and highest frequencies the zone plays, in Hz.
+    pub keys: (Float, Float),
+    /// Lowest and highest velocities the zone plays, from 0 to 1.
+    pub velocities: (Float, Float),
+    /// Part of the recording repeated while the note is held.
+    pub sustain_loop: Option<SampleLoop>,
+    /// Only the release is used: notes start with the recording and last as long as it does
+    /// without a loop.
+    pub envelope: Envelope,
+}
+
+impl Zone {
+    fn plays(&self, freq: Float, velocity: Float) -> bool {
+        (self.keys.0..=self.keys.1).contains(&freq)
+            && (self.velocities.0..=self.velocities.1).contains(&velocity)
+    }
+}
+
+/// The zone whose root is the closest to `freq`, in octaves.
+fn closest<'a>(zones: impl Iterator<Item = &'a Zone>, freq: Float) -> Option<&'a Zone> {
+    let distance = |zone: &Zone| (freq / zone.root).log2().abs();
+    zones.min_by(|a, b| distance(a).total_cmp(&distance(b)))
+}
+
+/// Recordings pitched to the notes played by resampling them.
+#[derive(Clone, Debug, Default)]
+pub struct Sampler {
+    pub zones: Vec<Zone>,
+}
+
+impl Sampler {
+    /// The first zone playing the note, or if there's none, the zone with the root closest to
+    /// it among those playing its velocity, or among all of them.
+    fn zone(&self, freq: Float, velocity: Float) -> Option<&Zone> {
+        let plays_velocity =
+            |zone: &&Zone| (zone.velocities.0..=zone.velocities.1).contains(&velocity);
+        self.zones
+            .iter()
+            .find(|zone| zone.plays(freq, velocity))
+            .or_else(|| closest(self.zones.iter().filter(plays_velocity), freq))
+            .or_else(|| closest(self.zones.iter(), freq))
+    }
+
+    /// Renders a note held for `samples` samples, followed by the release of its zone. It
+    /// stops early when the recording ends.
+    pub fn render(
+        &self,
+        freq: &Function,
+        amp: &Function,
+        velocity: Float,
+        samples: usize,
+        samplerate: u32,
+    ) -> Option<AudioWave> {
+        let Some(zone) = self.zone(freq.get(0.0), velocity) else {
+            return Some(AudioWave::from_samples(Vec::new(), samplerate));
+        };
+        let recording = &zone.sample;
+        let rate = samplerate as Float;
+        let held = samples as Float / rate;
+        let length = samples + (zone.envelope.release * rate).round() as usize;
+        // samples of the recording to move forward per output sample and Hz of the note
+        let speed = zone.samplerate as f64 / samplerate as f64 / to_f64(zone.root);
+
+        let mut position: f64 = 0.0;
+        let mut wave = Vec::with_capacity(length);
+        for n in 0..length {
+            let t = (n as f64 / samplerate as f64) as Float;
+            if t < held {
+                if let Some(SampleLoop { start, end }) = zone.sustain_loop {
+                    if position >= end as f64 {
+                        position -= (end - start) as f64;
+                    }
+                }
+            }
+            if position >= recording.len() as f64 {
+                break;
+            }
+            let i = position as usize;
+            let value = Interpolation::Cubic.at(recording, i, (position - i as f64) as Float);
+            wave.push(value * amp.get(t) * zone.envelope.level(t, held));
+            position += to_f64(freq.get(t)) * speed;
+        }
+        Some(AudioWave::from_samples(wave, samplerate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+
+    /// A zone holding the samples 0, 1, 2... of a recording at the output sample rate, and
+    /// the same frequency as notes of `root` Hz, so they play it back sample by sample.
+    fn zone(root: Float, keys: (Float, Float), velocities: (Float, Float)) -> Zone {
+        Zone {
+            sample: Arc::new((0..1000).map(|i| i as Float).collect()),
+            samplerate: SAMPLERATE,
+            root,
+            keys,
+            velocities,
+            sustain_loop: None,
+            envelope: Envelope {
+                attack: 0.0,
+                decay: 0.0,
+                sustain: 1.0,
+                release: 0.0,
+            },
+        }
+    }
+
+    fn play(zone: Zone, samples: usize) -> Vec<Float> {
+        let sampler = Sampler { zones: vec![zone] };
+        let note = Function::Const(sampler.zones[0].root);
+        sampler
+            .render(&note, &Function::Const(1.0), 1.0, samples, SAMPLERATE)
+            .unwrap()
+            .wave
+    }
+
+    #[test]
+    fn zones_are_picked_by_key_and_velocity() {
+        let sampler = Sampler {
+            zones: vec![
+                zone(200.0, (0.0, 300.0), (0.0, 0.5)),
+                zone(250.0, (0.0, 300.0), (0.5, 1.0)),
+                zone(400.0, (300.0, 500.0), (0.0, 1.0)),
+                zone(2000.0, (1500.0, 3000.0), (0.0, 0.2)),
+            ],
+        };
+        let root = |freq: Float, velocity: Float| sampler.zone(freq, velocity).unwrap().root;
+        assert_eq!(root(100.0, 0.2), 200.0);
+        assert_eq!(root(100.0, 0.8), 250.0);
+        assert_eq!(root(450.0, 0.8), 400.0);
+        assert_eq!(root(2000.0, 0.1), 2000.0);
+        // played by no zone: the closest root playing the velocity, or the closest root
+        assert_eq!(root(1000.0, 0.8), 400.0);
+        assert_eq!(root(1800.0, 0.8), 400.0);
+        assert_eq!(root(4000.0, 0.8), 400.0);
+        assert_eq!(root(4000.0, 0.1), 2000.0);
+        assert!(Sampler::default().zone(440.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn loops_repeat_while_the_note_is_held() {
+        let mut looped = zone(440.0, (0.0, Float::MAX), (0.0, 1.0));
+        looped.sustain_loop = Some(SampleLoop {
+            start: 100,
+            end: 200,
+        });
+        looped.envelope.release = 0.01;
+        let release = (0.01 * SAMPLERATE as Float).round() as usize;
+        let wave = play(looped, 2500);
+        assert_eq!(wave.len(), 2500 + release);
+        for (n, x) in wave.iter().enumerate().take(2500) {
+            let expected = if n < 200 { n } else { 100 + (n - 100) % 100 };
+            assert!((x - expected as Float).abs() < 1e-3, "sample {}", n);
+        }
+        // once let go, the recording plays on past the loop, fading out
+        for (k, x) in wave[2500..].iter().enumerate() {
+            let expected = (200 + k) as Float * (1.0 - k as Float / release as Float);
+            assert!((x - expected).abs() < 0.1, "sample {} of the release", k);
+        }
+    }
+
+    #[test]
+    fn notes_stop_with_the_recording_without_a_loop() {
+        let wave = play(zone(440.0, (0.0, Float::MAX), (0.0, 1.0)), 2500);
+        assert_eq!(wave.len(), 1000);
+        assert!((wave[999] - 999.0).abs() < 1e-3);
+    }
+}
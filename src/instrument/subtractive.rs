@@ -0,0 +1,196 @@
+use super::{Envelope, Waveform};
+use crate::audiowave::AudioWave;
+use crate::definitions::{to_f64, Float};
+use crate::function::Function;
+use std::f64::consts::PI;
+
+/// Pitch at which key tracking leaves the cutoff as it is: middle C, in Hz.
+const KEY_TRACKING_CENTER: Float = 261.626;
+
+/// Resonant low-pass filters a subtractive synth can use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterModel {
+    /// A 12 dB per octave state-variable filter, clean even at high resonance.
+    StateVariable,
+    /// A 24 dB per octave ladder like in analog synths, saturating and losing some bass as
+    /// the resonance goes up.
+    Ladder,
+}
+
+/// An oscillator going through a resonant low-pass filter whose cutoff moves with its own
+/// envelope and with the pitch of the note.
+#[derive(Clone, Copy, Debug)]
+pub struct Subtractive {
+    pub waveform: Waveform,
+    pub model: FilterModel,
+    /// Cutoff with the filter envelope at 0, for a note at middle C, in Hz.
+    pub cutoff: Float,
+    /// From 0 to 1, where the filter is about to oscillate on its own.
+    pub resonance: Float,
+    /// Hz added to the cutoff by the filter envelope at its peak.
+    pub envelope_amount: Float,
+    pub filter_envelope: Envelope,
+    /// How much the cutoff follows the pitch: with 1 it goes up an octave with each octave.
+    pub key_tracking: Float,
+    pub amp_envelope: Envelope,
+}
+
+impl Default for Subtractive {
+    fn default() -> Self {
+        Subtractive {
+            waveform: Waveform::Saw,
+            model: FilterModel::StateVariable,
+            cutoff: 800.0,
+            resonance: 0.3,
+            envelope_amount: 2000.0,
+            filter_envelope: Envelope {
+                attack: 0.005,
+                decay: 0.3,
+                sustain: 0.0,
+                release: 0.1,
+            },
+            key_tracking: 0.5,
+            amp_envelope: Envelope::default(),
+        }
+    }
+}
+
+/// State of the filter of a note, kept from one sample to the next.
+struct ResonantFilter {
+    model: FilterModel,
+    resonance: f64,
+    state: [f64; 4],
+}
+
+impl ResonantFilter {
+    fn new(model: FilterModel, resonance: Float) -> Self {
+        ResonantFilter {
+            model,
+            resonance: to_f64(resonance).clamp(0.0, 1.0),
+            state: [0.0; 4],
+        }
+    }
+
+    /// Filters one sample with the cutoff given as a fraction of the sample rate.
+    fn process(&mut self, x: f64, cutoff: f64) -> f64 {
+        match self.model {
+            FilterModel::StateVariable => {
+                // trapezoidal integrators keep the filter stable while the cutoff moves
+                let g = (PI * cutoff).tan();
+                let k = 2.0 - 1.98 * self.resonance;
+                let a1 = 1.0 / (1.0 + g * (g + k));
+                let a2 = g * a1;
+                let a3 = g * a2;
+                let [ic1, ic2, _, _] = self.state;
+                let v3 = x - ic2;
+                let v1 = a1 * ic1 + a2 * v3;
+                let v2 = ic2 + a2 * ic1 + a3 * v3;
+                self.state[0] = 2.0 * v1 - ic1;
+                self.state[1] = 2.0 * v2 - ic2;
+                v2
+            }
+            FilterModel::Ladder => {
+                // four one-pole stages, the output fed back into a saturating input
+                let g = 1.0 - (-2.0 * PI * cutoff).exp();
+                let mut input = (x - 4.0 * self.resonance * self.state[3]).tanh();
+                for stage in &mut self.state {
+                    *stage += g * (input - *stage);
+                    input = *stage;
+                }
+                input
+            }
+        }
+    }
+}
+
+impl Subtractive {
+    /// Renders a note held for `samples` samples, followed by the release of its amplitude
+    /// envelope.
+    pub fn render(
+        &self,
+        freq: &Function,
+        amp: &Function,
+        samples: usize,
+        samplerate: u32,
+    ) -> Option<AudioWave> {
+        let rate = samplerate as Float;
+        let held = samples as Float / rate;
+        let release = (self.amp_envelope.release * rate).round() as usize;
+        let mut wave = AudioWave::with_samples(
+            freq,
+            &Function::Const(1.0),
+            samples + release,
+            Some(samplerate),
+            Some(self.waveform.function()),
+        )?;
+        let mut filter = ResonantFilter::new(self.model, self.resonance);
+        for (n, sample) in wave.wave.iter_mut().enumerate() {
+            let t = (n as f64 / samplerate as f64) as Float;
+            let tracking = (freq.get(t) / KEY_TRACKING_CENTER).powf(self.key_tracking);
+            let cutoff =
+                self.cutoff * tracking + self.envelope_amount * self.filter_envelope.level(t, held);
+            let cutoff = cutoff.clamp(20.0, 0.45 * rate) / rate;
+            let filtered = filter.process(to_f64(*sample), to_f64(cutoff)) as Float;
+            *sample = filtered * amp.get(t) * self.amp_envelope.level(t, held);
+        }
+        Some(wave)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+    use crate::instrument::tests::{fundamental, play, rms};
+    use crate::instrument::Instrument;
+
+    fn synth(model: FilterModel) -> Instrument {
+        Instrument::Subtractive(Subtractive {
+            model,
+            amp_envelope: Envelope {
+                attack: 0.01,
+                decay: 0.1,
+                sustain: 0.7,
+                release: 0.25,
+            },
+            ..Subtractive::default()
+        })
+    }
+
+    #[test]
+    fn notes_ring_for_their_release() {
+        for model in [FilterModel::StateVariable, FilterModel::Ladder] {
+            let wave = play(&synth(model), 220.0, 0.5);
+            let rate = SAMPLERATE as usize;
+            assert_eq!(wave.len(), rate / 2 + rate / 4);
+            let (held, tail) = wave.split_at(rate / 2);
+            assert!(rms(&held[rate / 4..]) > 0.05);
+            // the release fades the sound out to nothing
+            assert!(rms(&tail[..rate / 20]) > rms(&tail[tail.len() - rate / 20..]) * 4.0);
+            assert!(tail.last().unwrap().abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn notes_play_at_their_pitch() {
+        for model in [FilterModel::StateVariable, FilterModel::Ladder] {
+            for freq in [110.0, 440.0, 1000.0] {
+                let wave = play(&synth(model), freq, 0.3);
+                let measured = fundamental(&wave[SAMPLERATE as usize / 10..]);
+                assert!((measured / freq - 1.0).abs() < 0.005, "{} Hz", measured);
+            }
+        }
+    }
+
+    #[test]
+    fn the_cutoff_follows_its_envelope() {
+        // the filter opens on the attack and closes as its envelope decays to 0
+        let wave = play(&synth(FilterModel::StateVariable), 110.0, 1.0);
+        let brightness = |part: &[Float]| {
+            let slope: Vec<Float> = part.windows(2).map(|w| w[1] - w[0]).collect();
+            rms(&slope) / rms(part)
+        };
+        let rate = SAMPLERATE as usize;
+        assert!(brightness(&wave[rate / 50..rate / 10]) > 1.5 * brightness(&wave[rate / 2..]));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::audiowave::{AudioWave, WavImportError};
+use crate::definitions::{to_f64, Float};
+use crate::function::{Function, Interpolation};
+use std::f64::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Number of samples frames are resampled to, so they can be band-limited with an FFT.
+const FRAME_SIZE: usize = 2048;
+
+/// In-place radix-2 FFT of complex values given as `(re, im)`. The inverse isn't scaled.
+fn fft(values: &mut [(f64, f64)], inverse: bool) {
+    let n = values.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            values.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (re, im) = values[start + k + len / 2];
+                let odd = (re * cos - im * sin, re * sin + im * cos);
+                let even = values[start + k];
+                values[start + k] = (even.0 + odd.0, even.1 + odd.1);
+                values[start + k + len / 2] = (even.0 - odd.0, even.1 - odd.1);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Copies of a cycle of `FRAME_SIZE` samples keeping fewer and fewer harmonics: the k-th
+/// one keeps the first `(FRAME_SIZE / 2) >> k`, down to the fundamental alone.
+fn band_limit(cycle: &[f64]) -> Vec<Vec<Float>> {
+    let mut spectrum: Vec<(f64, f64)> = cycle.iter().map(|x| (*x, 0.0)).collect();
+    fft(&mut spectrum, false);
+    (0..FRAME_SIZE.trailing_zeros())
+        .map(|k| {
+            let harmonics = (FRAME_SIZE / 2) >> k;
+            let mut limited: Vec<(f64, f64)> = spectrum
+                .iter()
+                .enumerate()
+                .map(|(bin, value)| {
+                    if bin <= harmonics || bin >= FRAME_SIZE - harmonics {
+                        *value
+                    } else {
+                        (0.0, 0.0)
+                    }
+                })
+                .collect();
+            fft(&mut limited, true);
+            limited
+                .iter()
+                .map(|(re, _)| (re / FRAME_SIZE as f64) as Float)
+                .collect()
+        })
+        .collect()
+}
+
+/// An oscillator reading single cycles from a table, morphing from one to the next as its
+/// position moves. Each cycle is band-limited per octave, so high notes don't alias.
+#[derive(Clone, Debug)]
+pub struct Wavetable {
+    /// Band-limited copies of each frame, as made by `band_limit`, shared with the other
+    /// instruments reading the same table.
+    frames: Arc<Vec<Vec<Vec<Float>>>>,
+    pub interpolation: Interpolation,
+    /// Position in the table at the start and at the end of each note, from 0 for the first
+    /// frame to 1 for the last one.
+    pub position: (Float, Float),
+}
+
+impl Wavetable {
+    /// Reads a table from a WAV file made of single cycles of `frame` samples one after the
+    /// other. A file shorter than a frame is taken as a single cycle, and a longer one must hold
+    /// a whole number of frames.
+    pub fn load(path: &Path, frame: usize) -> Result<Wavetable, WavImportError> {
+        let wave = AudioWave::from_wav(path)?.wave;
+        if wave.is_empty() {
+            return Err(WavImportError::ParseError("the file is empty".to_owned()));
+        }
+        let frame = frame.clamp(1, wave.len());
+        if wave.len() % frame != 0 {
+            return Err(WavImportError::ParseError(format!(
+                "the file has {} samples, which is not a whole number of frames of {} samples",
+                wave.len(),
+                frame
+            )));
+        }
+        let frames = wave
+            .chunks_exact(frame)
+            .map(|cycle| {
+                let step = cycle.len() as Float / FRAME_SIZE as Float;
+                let resampled: Vec<f64> = (0..FRAME_SIZE)
+                    .map(|i| to_f64(Interpolation::Cubic.looped(cycle, i as Float * step)))
+                    .collect();
+                band_limit(&resampled)
+            })
+            .collect();
+        Ok(Wavetable {
+            frames: Arc::new(frames),
+            interpolation: Interpolation::Cubic,
+            position: (0.0, 0.0),
+        })
+    }
+
+    /// Renders a note lasting `samples` samples.
+    pub fn render(
+        &self,
+        freq: &Function,
+        amp: &Function,
+        samples: usize,
+        samplerate: u32,
+    ) -> Option<AudioWave> {
+        let held = samples as Float / samplerate as Float;
+        let nyquist = samplerate as f64 / 2.0;
+        let levels = self.frames[0].len();
+        let mut phase: f64 = 0.0;
+        let mut wave = Vec::with_capacity(samples);
+        let dt = 1.0 / samplerate as f64;
+        for n in 0..samples {
+            let t = (n as f64 / samplerate as f64) as Float;
+            let f = to_f64(freq.get(t));
+            phase = (phase + f * dt).rem_euclid(1.0);
+            // the copy with the most harmonics all below Nyquist
+            let harmonics = (nyquist / f.abs().max(1.0)) as usize;
+            let level = (0..levels)
+                .find(|k| (FRAME_SIZE / 2) >> k <= harmonics)
+                .unwrap_or(levels - 1);
+
+            let progress = if held > 0.0 { t / held } else { 0.0 };
+            let position = self.position.0 + (self.position.1 - self.position.0) * progress;
+            let position = position.clamp(0.0, 1.0) * (self.frames.len() - 1) as Float;
+            let i = (position as usize).min(self.frames.len() - 1);
+            let x = position - i as Float;
+            let index = (phase * FRAME_SIZE as f64) as Float;
+            let read = |frame: usize| self.interpolation.looped(&self.frames[frame][level], index);
+            let value = if x > 0.0 {
+                read(i) * (1.0 - x) + read(i + 1) * x
+            } else {
+                read(i)
+            };
+            wave.push(value * amp.get(t));
+        }
+        Some(AudioWave::from_samples(wave, samplerate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::SAMPLERATE;
+
+    /// A cycle of a saw, whose n-th harmonic has an amplitude of 1/n.
+    fn saw() -> Vec<f64> {
+        (0..FRAME_SIZE)
+            .map(|i| 1.0 - 2.0 * i as f64 / FRAME_SIZE as f64)
+            .collect()
+    }
+
+    /// Amplitude of the sine at `freq` Hz in `wave`.
+    fn amplitude(wave: &[Float], freq: f64) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (n, x) in wave.iter().enumerate() {
+            let (sin, cos) = (2.0 * PI * freq * n as f64 / SAMPLERATE as f64).sin_cos();
+            re += to_f64(*x) * cos;
+            im += to_f64(*x) * sin;
+        }
+        2.0 * (re * re + im * im).sqrt() / wave.len() as f64
+    }
+
+    #[test]
+    fn each_octave_keeps_half_the_harmonics() {
+        let levels = band_limit(&saw());
+        assert_eq!(levels.len(), 11);
+        for (k, level) in levels.iter().enumerate() {
+            let kept = (FRAME_SIZE / 2) >> k;
+            let mut spectrum: Vec<(f64, f64)> = level.iter().map(|x| (to_f64(*x), 0.0)).collect();
+            fft(&mut spectrum, false);
+            for (harmonic, (re, im)) in spectrum.iter().enumerate().take(FRAME_SIZE / 2).skip(1) {
+                let amplitude = 2.0 * (re * re + im * im).sqrt() / FRAME_SIZE as f64;
+                let expected = if harmonic <= kept {
+                    2.0 / (PI * harmonic as f64)
+                } else {
+                    0.0
+                };
+                assert!(
+                    (amplitude - expected).abs() < 1e-3,
+                    "harmonic {} of level {}",
+                    harmonic,
+                    k
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn notes_only_play_harmonics_below_nyquist() {
+        let table = Wavetable {
+            frames: Arc::new(vec![band_limit(&saw())]),
+            interpolation: Interpolation::Cubic,
+            position: (0.0, 0.0),
+        };
+        // 7 harmonics of 3 kHz fit below 22.05 kHz, so the copy keeping 4 is played
+        let freq = 3000.0;
+        let wave = table
+            .render(
+                &Function::Const(freq as Float),
+                &Function::Const(1.0),
+                44100,
+                SAMPLERATE,
+            )
+            .unwrap()
+            .wave;
+        for harmonic in 1..=4 {
+            let expected = 2.0 / (PI * harmonic as f64);
+            assert!((amplitude(&wave, freq * harmonic as f64) - expected).abs() < 0.01);
+        }
+        // higher harmonics, and the 8th and 9th folded back under Nyquist, are left out
+        for freq in [15000.0, 18000.0, 21000.0, 20100.0, 17100.0] {
+            assert!(amplitude(&wave, freq) < 1e-3, "{} Hz", freq);
+        }
+    }
+}
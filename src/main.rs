@@ -3,7 +3,10 @@
 mod audiowave;
 mod definitions;
 mod function;
+mod instrument;
 mod parser;
+mod random;
+mod timeline;
 
 use crate::parser::{Manager};
 
@@ -47,7 +50,11 @@ _; C-; G-; C; Eb 1; glissando Ab A#; A#; A; G; A 1; A#; G; Eb; C; B-; C; D 1; Eb
 
 
     let mut m = Manager::new();
-    match m.run(x){
+    let result = match std::env::args().nth(1) {
+        Some(path) => m.run_file(std::path::Path::new(&path)),
+        None => m.run(x),
+    };
+    match result {
         Ok(v) => println!("{:?}", v.wave),
         Err(e) => println!("{}",e),
     }
@@ -0,0 +1,204 @@
+use crate::definitions::Float;
+use std::collections::HashMap;
+
+/// Evaluates an arithmetic expression such as `beat*3/2` or `(base + 10) / 2`.
+/// Supports numbers, variables, parentheses, unary signs and `+ - * /`.
+pub fn evaluate(text: &str, variables: &HashMap<String, Float>) -> Result<Float, String> {
+    let mut parser = ExprParser {
+        chars: text.chars().collect(),
+        pos: 0,
+        variables,
+    };
+    if text.trim().is_empty() {
+        return Err("Missing expression".to_owned());
+    }
+    let value = parser.sum()?;
+    if parser.peek().is_some() {
+        return Err(format!(
+            "Unexpected '{}' in expression '{}'",
+            parser.chars[parser.pos], text
+        ));
+    }
+    Ok(value)
+}
+
+/// Returns whether `name` can be used as a variable: it starts with a lowercase letter, so it
+/// can't be confused with a note, followed by letters, digits or underscores.
+pub fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses the arguments of a `let` line (`beat = 0.5`) into the name and value of a variable.
+pub fn parse_let(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<(String, Float), String> {
+    let text = words.join(" ");
+    let (name, value) = text
+        .split_once('=')
+        .ok_or("Expected 'let name = expression'")?;
+    let name = name.trim();
+    if !is_valid_name(name) {
+        return Err(format!("'{}' is not a valid variable name", name));
+    }
+    Ok((name.to_owned(), evaluate(value, variables)?))
+}
+
+struct ExprParser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    variables: &'a HashMap<String, Float>,
+}
+
+impl ExprParser<'_> {
+    /// Returns the next character that isn't whitespace, skipping whitespace.
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Returns whether the character right after the previous one satisfies `f`.
+    fn next_is(&self, f: impl Fn(char) -> bool) -> bool {
+        self.chars.get(self.pos).is_some_and(|c| f(*c))
+    }
+
+    fn sum(&mut self) -> Result<Float, String> {
+        let mut value = self.product()?;
+        while let Some(op) = self.peek() {
+            match op {
+                '+' => {
+                    self.pos += 1;
+                    value += self.product()?;
+                }
+                '-' => {
+                    self.pos += 1;
+                    value -= self.product()?;
+                }
+                _ => break,
+            }
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<Float, String> {
+        let mut value = self.factor()?;
+        while let Some(op) = self.peek() {
+            match op {
+                '*' => {
+                    self.pos += 1;
+                    value *= self.factor()?;
+                }
+                '/' => {
+                    self.pos += 1;
+                    let divisor = self.factor()?;
+                    if divisor == 0.0 {
+                        return Err("Division by zero".to_owned());
+                    }
+                    value /= divisor;
+                }
+                _ => break,
+            }
+        }
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> Result<Float, String> {
+        match self.peek() {
+            None => Err("Unexpected end of expression".to_owned()),
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.factor()?)
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.factor()
+            }
+            Some('(') => {
+                self.pos += 1;
+                let value = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err("Missing ')' in expression".to_owned());
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.pos;
+                while self.next_is(|c| c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number
+                    .parse::<Float>()
+                    .map_err(|_| format!("'{}' is not a valid number", number))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let start = self.pos;
+                while self.next_is(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+                self.variables
+                    .get(&name)
+                    .copied()
+                    .ok_or(format!("Unknown variable '{}'", name))
+            }
+            Some(c) => Err(format!("Unexpected '{}' in expression", c)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<Float, String> {
+        let variables = HashMap::from([("beat".to_owned(), 0.5), ("base2".to_owned(), 3.0)]);
+        evaluate(text, &variables)
+    }
+
+    #[test]
+    fn products_bind_tighter_than_sums() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(eval("8 / 4 / 2"), Ok(1.0));
+        assert_eq!(eval("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(eval("beat*3/2"), Ok(0.75));
+    }
+
+    #[test]
+    fn unary_signs_apply_to_the_next_factor() {
+        assert_eq!(eval("-2 * 3"), Ok(-6.0));
+        assert_eq!(eval("-(1 + 2)"), Ok(-3.0));
+        assert_eq!(eval("4 - -base2"), Ok(7.0));
+        assert_eq!(eval("+beat"), Ok(0.5));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        assert_eq!(eval("1 / (beat - 0.5)"), Err("Division by zero".to_owned()));
+        assert_eq!(
+            eval("beats * 2"),
+            Err("Unknown variable 'beats'".to_owned())
+        );
+        assert_eq!(eval(""), Err("Missing expression".to_owned()));
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("1 + ").is_err());
+        assert!(eval("2 3").is_err());
+    }
+
+    #[test]
+    fn let_lines_define_valid_names() {
+        let words: Vec<String> = ["half", "=", "beat", "*", "2"].map(String::from).to_vec();
+        let variables = HashMap::from([("beat".to_owned(), 0.5)]);
+        assert_eq!(parse_let(&words, &variables), Ok(("half".to_owned(), 1.0)));
+        assert!(parse_let(
+            &["C".to_owned(), "=".to_owned(), "1".to_owned()],
+            &variables
+        )
+        .is_err());
+    }
+}
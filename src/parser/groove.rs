@@ -0,0 +1,245 @@
+use super::{expr, rhythm};
+use crate::definitions::Float;
+use std::collections::HashMap;
+
+/// A rhythmic feel applied to a voice by moving note onsets off the straight grid.
+///
+/// The grid is made of slots one `subdivision` long, repeating every `offsets.len()` slots.
+/// The onset of each slot is moved by its offset (in slots), and positions between slots are
+/// interpolated, so note durations stretch and shrink to keep notes back to back.
+/// Notes starting on a slot are also scaled by its accent.
+#[derive(Clone, Debug)]
+pub struct Groove {
+    subdivision: Float,
+    offsets: Vec<Float>,
+    accents: Vec<Float>,
+}
+
+impl Groove {
+    /// Swing on the given subdivision, where `ratio` is the part of a pair of subdivisions taken
+    /// by the first one: 0.5 is straight and 0.66 is close to a triplet feel.
+    pub fn swing(subdivision: Float, ratio: Float) -> Result<Groove, String> {
+        if !(0.0..1.0).contains(&ratio) || ratio == 0.0 {
+            return Err(format!("'{}' is not a valid swing ratio", ratio));
+        }
+        Ok(Groove {
+            subdivision,
+            offsets: vec![0.0, 2.0 * ratio - 1.0],
+            accents: vec![1.0, 1.0],
+        })
+    }
+
+    /// Moves a position in beats to where it falls with this groove.
+    pub fn warp(&self, position: Float) -> Float {
+        let slots = self.offsets.len();
+        let slot_position = position / self.subdivision;
+        let slot = slot_position.floor();
+        let fraction = slot_position - slot;
+        let index = (slot as i64).rem_euclid(slots as i64) as usize;
+        let offset = self.offsets[index]
+            + (self.offsets[(index + 1) % slots] - self.offsets[index]) * fraction;
+        position + offset * self.subdivision
+    }
+
+    /// Returns the accent of a note starting at a position in beats, 1 if it is off the grid.
+    pub fn accent(&self, position: Float) -> Float {
+        let slot_position = position / self.subdivision;
+        let slot = slot_position.round();
+        if (slot_position - slot).abs() > 1e-3 {
+            return 1.0;
+        }
+        let index = (slot as i64).rem_euclid(self.accents.len() as i64) as usize;
+        self.accents[index]
+    }
+}
+
+fn parse_numbers(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Vec<Float>, String> {
+    words.iter().map(|w| expr::evaluate(w, variables)).collect()
+}
+
+/// Parses the arguments of a `groove` line. Returns `None` for `groove off`.
+///
+/// - `shuffle`: triplet swing on eighths.
+/// - `swing <ratio> [subdivision]`: swing on eighths or on the given subdivision.
+/// - `mpc <percentage>`: swing on sixteenths given as on MPC drum machines, 50 being straight.
+/// - `custom <subdivision> offsets <o1> <o2>... [accents <a1> <a2>...]`: offsets in slots.
+pub fn parse(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Option<Groove>, String> {
+    let kind = words.first().ok_or("Missing groove name")?;
+    match kind.as_str() {
+        "off" => Ok(None),
+        "shuffle" => Groove::swing(0.5, 2.0 / 3.0).map(Some),
+        "swing" => {
+            let ratio = words.get(1).ok_or("Missing swing ratio")?;
+            let ratio = expr::evaluate(ratio, variables)?;
+            let subdivision = match words.get(2) {
+                Some(w) => rhythm::parse_duration(w, variables)?,
+                None => 0.5,
+            };
+            Groove::swing(subdivision, ratio).map(Some)
+        }
+        "mpc" => {
+            let percentage = words.get(1).ok_or("Missing swing percentage")?;
+            let percentage = expr::evaluate(percentage, variables)?;
+            Groove::swing(0.25, percentage / 100.0).map(Some)
+        }
+        "custom" => {
+            let subdivision = words.get(1).ok_or("Missing groove subdivision")?;
+            let subdivision = rhythm::parse_duration(subdivision, variables)?;
+            if words.get(2).map(|w| w.as_str()) != Some("offsets") {
+                return Err("Expected 'offsets' after the groove subdivision".to_owned());
+            }
+            let accents_at = words.iter().position(|w| w == "accents");
+            let offsets = parse_numbers(&words[3..accents_at.unwrap_or(words.len())], variables)?;
+            if offsets.is_empty() {
+                return Err("A groove needs at least one offset".to_owned());
+            }
+            if offsets.iter().any(|o| o.abs() >= 0.5) {
+                return Err("Groove offsets must be smaller than half a slot".to_owned());
+            }
+            let accents = match accents_at {
+                Some(i) => parse_numbers(&words[i + 1..], variables)?,
+                None => vec![1.0; offsets.len()],
+            };
+            if accents.len() != offsets.len() {
+                return Err("A groove needs as many accents as offsets".to_owned());
+            }
+            if subdivision <= 0.0 {
+                return Err("The groove subdivision must be positive".to_owned());
+            }
+            Ok(Some(Groove {
+                subdivision,
+                offsets,
+                accents,
+            }))
+        }
+        other => Err(format!("Unknown groove '{}'", other)),
+    }
+}
+
+/// Keeps track of where notes fall as a voice is played, in beats from its start.
+#[derive(Default)]
+pub struct Clock {
+    /// Where the next note starts on the straight grid.
+    position: Float,
+    /// Where the previous note actually ended.
+    end: Float,
+    pub groove: Option<Groove>,
+}
+
+impl Clock {
+    /// Moves past a note lasting `beats` on the straight grid, returning how long it actually
+    /// lasts with the groove and its accent.
+    pub fn advance(&mut self, beats: Float) -> (Float, Float) {
+        let (end, accent) = match &self.groove {
+            Some(g) => (g.warp(self.position + beats), g.accent(self.position)),
+            None => (self.position + beats, 1.0),
+        };
+        let end = end.max(self.end);
+        let length = end - self.end;
+        self.position += beats;
+        self.end = end;
+        (length, accent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groove(text: &str) -> Result<Option<Groove>, String> {
+        let words: Vec<String> = text.split_whitespace().map(String::from).collect();
+        parse(&words, &HashMap::new())
+    }
+
+    /// Lengths of notes of the given durations played one after the other with the groove.
+    fn lengths(groove: Groove, durations: &[Float]) -> Vec<Float> {
+        let mut clock = Clock {
+            groove: Some(groove),
+            ..Clock::default()
+        };
+        durations.iter().map(|d| clock.advance(*d).0).collect()
+    }
+
+    fn assert_close(actual: &[Float], expected: &[Float]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-4, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn swing_moves_the_off_beat() {
+        let swing = Groove::swing(0.5, 0.66).unwrap();
+        assert!((swing.warp(0.5) - 0.66).abs() < 1e-4);
+        assert!((swing.warp(2.5) - 2.66).abs() < 1e-4);
+        assert_close(&lengths(swing, &[0.5; 4]), &[0.66, 0.34, 0.66, 0.34]);
+    }
+
+    #[test]
+    fn notes_stay_back_to_back() {
+        let durations = [0.25, 0.75, 1.0 / 3.0, 0.5, 2.0 / 3.0, 0.125, 0.375, 1.0];
+        let grooves = [
+            Groove::swing(0.5, 0.66).unwrap(),
+            groove("mpc 58").unwrap().unwrap(),
+            groove("custom e offsets 0 0.2 -0.1").unwrap().unwrap(),
+        ];
+        for groove in grooves {
+            let mut position = 0.0;
+            let mut end = 0.0;
+            for (duration, length) in durations.iter().zip(lengths(groove.clone(), &durations)) {
+                // each note starts where the previous one ends, where the grid is warped to
+                assert!(length >= 0.0);
+                position += duration;
+                end += length;
+                assert!((end - groove.warp(position)).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn templates_apply_their_offsets_and_accents() {
+        let mpc = groove("mpc 66").unwrap().unwrap();
+        assert_close(&lengths(mpc, &[0.25; 4]), &[0.33, 0.17, 0.33, 0.17]);
+
+        let custom = groove("custom e offsets 0 0.2 -0.1 accents 1 0.5 0.8")
+            .unwrap()
+            .unwrap();
+        let warped: Vec<Float> = [0.25, 0.5, 1.0, 1.5].map(|p| custom.warp(p)).to_vec();
+        assert_close(&warped, &[0.3, 0.6, 0.95, 1.5]);
+        let accents: Vec<Float> = [0.0, 0.25, 0.5, 1.0, 1.5]
+            .map(|p| custom.accent(p))
+            .to_vec();
+        assert_close(&accents, &[1.0, 1.0, 0.5, 0.8, 1.0]);
+        assert_close(&lengths(custom, &[0.5; 3]), &[0.6, 0.35, 0.55]);
+        assert!(groove("off").unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_grooves_are_rejected() {
+        let lines = [
+            "",
+            "bounce",
+            "swing",
+            "swing 0",
+            "swing 1",
+            "swing 0.6 x",
+            "mpc",
+            "mpc 120",
+            "custom",
+            "custom e 0 0.1",
+            "custom e offsets",
+            "custom e offsets 0 0.5",
+            "custom e offsets 0 0.1 accents 1",
+            "custom 0 offsets 0 0.1",
+        ];
+        for line in lines {
+            assert!(groove(line).is_err(), "'{}' was accepted", line);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use super::expr;
+use crate::definitions::Float;
+use crate::random::Random;
+use std::collections::HashMap;
+
+/// Random deviations in timing and loudness, making a voice sound less mechanical.
+/// The same seed always gives the same deviations.
+#[derive(Clone, Debug)]
+pub struct Humanizer {
+    /// Largest deviation of an onset, in seconds.
+    time: Float,
+    /// Largest deviation of the velocity, as a fraction of it.
+    velocity: Float,
+    random: Random,
+}
+
+impl Humanizer {
+    pub fn new(time: Float, velocity: Float, seed: u64) -> Self {
+        Humanizer {
+            time,
+            velocity,
+            random: Random::new(seed),
+        }
+    }
+
+    /// Returns how early (negative) or late an onset is, in seconds.
+    pub fn next_offset(&mut self) -> Float {
+        self.random.next_signed() as Float * self.time
+    }
+
+    /// Returns a note velocity moved by up to the velocity amount, kept between 0 and 1.
+    pub fn next_velocity(&mut self, velocity: Float) -> Float {
+        (velocity * (1.0 + self.random.next_signed() as Float * self.velocity)).clamp(0.0, 1.0)
+    }
+}
+
+/// Parses a time like `8ms` or `0.01s` into seconds. Plain numbers are milliseconds.
+pub fn parse_time(word: &str, variables: &HashMap<String, Float>) -> Result<Float, String> {
+    if let Some(ms) = word.strip_suffix("ms") {
+        Ok(expr::evaluate(ms, variables)? / 1000.0)
+    } else if let Some(s) = word.strip_suffix('s') {
+        expr::evaluate(s, variables)
+    } else {
+        Ok(expr::evaluate(word, variables)? / 1000.0)
+    }
+}
+
+/// Parses the arguments of a `humanize` line, like `time 8ms velocity 0.1 seed 42`.
+/// Returns `None` for `humanize off`. Omitted amounts are 0 and the seed defaults to 0.
+pub fn parse(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Option<Humanizer>, String> {
+    if words.len() == 1 && words[0] == "off" {
+        return Ok(None);
+    }
+    let mut time: Float = 0.0;
+    let mut velocity: Float = 0.0;
+    let mut seed: u64 = 0;
+    let mut i = 0;
+    while i < words.len() {
+        let value = words
+            .get(i + 1)
+            .ok_or(format!("Missing value for '{}'", words[i]))?;
+        match words[i].as_str() {
+            "time" => time = parse_time(value, variables)?.abs(),
+            "velocity" => velocity = expr::evaluate(value, variables)?.abs(),
+            "seed" => {
+                seed = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a valid seed", value))?
+            }
+            other => return Err(format!("Unknown humanize setting '{}'", other)),
+        }
+        i += 2;
+    }
+    Ok(Some(Humanizer::new(time, velocity, seed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offsets and velocities of the first notes humanized with the seed, at full velocity.
+    fn deviations(seed: u64) -> Vec<(Float, Float)> {
+        let mut humanizer = Humanizer::new(0.02, 0.3, seed);
+        (0..1000)
+            .map(|_| (humanizer.next_offset(), humanizer.next_velocity(0.9)))
+            .collect()
+    }
+
+    #[test]
+    fn seeds_give_the_same_deviations() {
+        assert_eq!(deviations(42), deviations(42));
+        assert_ne!(deviations(42), deviations(43));
+    }
+
+    #[test]
+    fn deviations_stay_within_the_amounts() {
+        let deviations = deviations(7);
+        assert!(deviations.iter().all(|(offset, _)| offset.abs() <= 0.02));
+        assert!(deviations.iter().all(|(_, v)| (0.0..=1.0).contains(v)));
+        // the amounts are reached, not just respected
+        assert!(deviations.iter().any(|(offset, _)| offset.abs() > 0.015));
+        assert!(deviations.iter().any(|(_, v)| *v == 1.0));
+        assert!(deviations.iter().any(|(_, v)| *v < 0.7));
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        let humanizer = |text: &str| {
+            let words: Vec<String> = text.split_whitespace().map(String::from).collect();
+            parse(&words, &HashMap::new())
+        };
+        let parsed = humanizer("time 20ms velocity 0.3 seed 7").unwrap().unwrap();
+        assert_eq!((parsed.time, parsed.velocity), (0.02, 0.3));
+        assert!(humanizer("off").unwrap().is_none());
+        for line in ["time", "seed -1", "seed x", "pitch 3"] {
+            assert!(humanizer(line).is_err(), "'{}' was accepted", line);
+        }
+    }
+}
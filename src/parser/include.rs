@@ -0,0 +1,220 @@
+use std::path::{Path, PathBuf};
+
+/// Reads the path out of an `include "path"` line, if the line is one.
+fn included_path(line: &str) -> Option<Result<String, String>> {
+    let line = line.trim();
+    let rest = line.strip_prefix("include")?;
+    if !rest.starts_with(char::is_whitespace) && !rest.starts_with('"') {
+        return None;
+    }
+    let rest = rest.trim();
+    let path = rest
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+        .filter(|p| !p.is_empty() && !p.contains('"'));
+    Some(match path {
+        Some(p) => Ok(p.to_owned()),
+        None => Err(format!(
+            "Invalid syntax on line: {}\nExpected a path between double quotes",
+            line
+        )),
+    })
+}
+
+fn display_chain(chain: &[PathBuf], last: &Path) -> String {
+    chain
+        .iter()
+        .map(|p| p.display().to_string())
+        .chain(std::iter::once(last.display().to_string()))
+        .collect::<Vec<String>>()
+        .join(" -> ")
+}
+
+/// Returns the length of the statement at the start of `text`, up to the next `;` or `%`.
+/// The quoted path of an `include` line is skipped, since it may contain them.
+fn statement_end(text: &str) -> usize {
+    let mut from = 0;
+    if let Some(rest) = text.trim_start().strip_prefix("include") {
+        let rest = rest.trim_start();
+        if rest.starts_with('"') {
+            let open = text.len() - rest.len();
+            if let Some(close) = text[open + 1..].find('"') {
+                from = open + close + 2;
+            }
+        }
+    }
+    text[from..]
+        .find([';', '%'])
+        .map_or(text.len(), |i| from + i)
+}
+
+/// Replaces every `include "path";` in `text` by the contents of the file, recursively.
+/// Paths are relative to `dir`, the directory of the including file.
+/// `chain` holds the files currently being included, to report cycles.
+///
+/// A file can be included several times, e.g. by every voice using it. The sections it
+/// defines are then defined again with the same lines, which is allowed.
+pub fn resolve_includes(
+    text: &str,
+    dir: &Path,
+    chain: &mut Vec<PathBuf>,
+) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    loop {
+        let (line, tail) = rest.split_at(statement_end(rest));
+        match included_path(line) {
+            None => result.push_str(line),
+            Some(path) => result.push_str(&include(line, &path?, dir, chain)?),
+        }
+        match tail.chars().next() {
+            Some(separator) => {
+                result.push(separator);
+                rest = &tail[1..];
+            }
+            None => break,
+        }
+    }
+    Ok(result)
+}
+
+/// Reads the file included by `line` and resolves its own includes.
+fn include(line: &str, path: &str, dir: &Path, chain: &mut Vec<PathBuf>) -> Result<String, String> {
+    let path = dir.join(path);
+    let canonical = match path.canonicalize() {
+        Ok(p) => p,
+        Err(e) => {
+            return Err(format!(
+                "Error on line: {}\nCannot read '{}': {}",
+                line.trim(),
+                path.display(),
+                e
+            ))
+        }
+    };
+    if chain.contains(&canonical) {
+        return Err(format!(
+            "Error on line: {}\nInclude cycle: {}",
+            line.trim(),
+            display_chain(chain, &canonical)
+        ));
+    }
+    let contents = match std::fs::read_to_string(&canonical) {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(format!(
+                "Error on line: {}\nCannot read '{}': {}",
+                line.trim(),
+                path.display(),
+                e
+            ))
+        }
+    };
+    let parent = canonical.parent().unwrap_or(dir).to_path_buf();
+    chain.push(canonical);
+    let included = resolve_includes(&contents, &parent, chain);
+    chain.pop();
+    included
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::preprocess_file;
+    use std::path::PathBuf;
+
+    /// Writes the files into an empty directory and returns the path of the first one.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("amns-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let score = write_files(
+            "include-cycle",
+            &[
+                ("score.amns", "C; include \"a.amns\""),
+                ("a.amns", "include \"b.amns\"; D"),
+                ("b.amns", "include \"a.amns\""),
+            ],
+        );
+        let error = preprocess_file(&score).unwrap_err();
+        let file = |name: &str| score.with_file_name(name).canonicalize().unwrap();
+        let chain = [
+            file("score.amns"),
+            file("a.amns"),
+            file("b.amns"),
+            file("a.amns"),
+        ]
+        .map(|p| p.display().to_string())
+        .join(" -> ");
+        assert!(
+            error.ends_with(&format!("Include cycle: {}", chain)),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn libraries_can_be_included_by_several_voices() {
+        let score = write_files(
+            "include-twice",
+            &[
+                (
+                    "score.amns",
+                    "include \"bass.amns\" % include \"lead.amns\"",
+                ),
+                (
+                    "lib.amns",
+                    "global section riff; C; D; end; section fill; E; end",
+                ),
+                (
+                    "bass.amns",
+                    "include \"lib.amns\"; octave 2; jump riff; jump fill",
+                ),
+                (
+                    "lead.amns",
+                    "include \"lib.amns\"; include \"lib.amns\"; jump riff",
+                ),
+            ],
+        );
+        let voices = preprocess_file(&score).unwrap();
+        assert_eq!(voices, [vec!["octave 2", "C", "D", "E"], vec!["C", "D"]]);
+    }
+
+    #[test]
+    fn sections_cannot_be_defined_again_differently() {
+        let score = write_files(
+            "include-conflict",
+            &[
+                (
+                    "score.amns",
+                    "include \"lib.amns\"; global section riff; C; E; end",
+                ),
+                ("lib.amns", "global section riff; C; D; end"),
+            ],
+        );
+        let error = preprocess_file(&score).unwrap_err();
+        assert!(error.contains("already defined differently"), "{}", error);
+    }
+
+    #[test]
+    fn included_paths_can_contain_separators() {
+        let score = write_files(
+            "include-separators",
+            &[
+                ("score.amns", "include \"a;b%c.amns\"; D % E"),
+                ("a;b%c.amns", "C"),
+            ],
+        );
+        assert_eq!(
+            preprocess_file(&score).unwrap(),
+            [vec!["C", "D"], vec!["E"]]
+        );
+    }
+}
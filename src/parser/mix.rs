@@ -0,0 +1,298 @@
+use super::{expr, humanize};
+use crate::audiowave::{
+    db_to_gain, AudioWave, Compressor, Equalizer, Filter, FilterKind, Gate, Limiter, SoftClip,
+};
+use crate::definitions::Float;
+use std::collections::HashMap;
+
+/// Parses a level in decibels, like `-6`, `-6dB` or `-6 dB`.
+pub fn parse_db(words: &[String], variables: &HashMap<String, Float>) -> Result<Float, String> {
+    let text = words.join(" ");
+    let text = text.trim_end();
+    let text = text.strip_suffix("dB").unwrap_or(text);
+    expr::evaluate(text, variables)
+}
+
+/// Parses the arguments of a `limiter` line, like `ceiling -1 lookahead 5ms release 80ms`.
+/// Returns `None` for `limiter off`. Omitted settings keep their default.
+fn parse_limiter(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Option<Limiter>, String> {
+    if words.len() == 1 && words[0] == "off" {
+        return Ok(None);
+    }
+    let mut limiter = Limiter::default();
+    let mut i = 0;
+    while i < words.len() {
+        let value = words
+            .get(i + 1)
+            .ok_or(format!("Missing value for '{}'", words[i]))?;
+        match words[i].as_str() {
+            "ceiling" => limiter.ceiling = parse_db(&words[i + 1..i + 2], variables)?,
+            "lookahead" => limiter.lookahead = humanize::parse_time(value, variables)?.abs(),
+            "release" => limiter.release = humanize::parse_time(value, variables)?.abs(),
+            other => return Err(format!("Unknown limiter setting '{}'", other)),
+        }
+        i += 2;
+    }
+    Ok(Some(limiter))
+}
+
+/// Parses a positive frequency in Hz, like `1200`, `1200Hz` or `1.2kHz`.
+pub fn parse_frequency(word: &str, variables: &HashMap<String, Float>) -> Result<Float, String> {
+    let frequency = match word.strip_suffix("kHz") {
+        Some(khz) => expr::evaluate(khz, variables)? * 1000.0,
+        None => expr::evaluate(word.strip_suffix("Hz").unwrap_or(word), variables)?,
+    };
+    if frequency <= 0.0 {
+        return Err(format!("'{}' is not a valid frequency", word));
+    }
+    Ok(frequency)
+}
+
+/// Parses the settings of a filter: `<kind> <frequency> [q] [gain]`, like `lowpass 1200 0.7`
+/// or `peak 2.5kHz 1.4 -3dB`. The Q defaults to 0.707 and the gain of shelves and peaks to
+/// 0 dB.
+pub fn parse_filter(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Filter, String> {
+    if !(2..=4).contains(&words.len()) {
+        return Err("Expected '<kind> <frequency> [q] [gain]'".to_owned());
+    }
+    let kind = FilterKind::from_name(&words[0]).ok_or(format!("Unknown filter '{}'", words[0]))?;
+    let frequency = parse_frequency(&words[1], variables)?;
+    let q = match words.get(2) {
+        Some(w) => expr::evaluate(w, variables)?,
+        None => 0.707,
+    };
+    if q <= 0.0 {
+        return Err(format!("'{}' is not a valid Q", q));
+    }
+    let gain = match words.get(3) {
+        Some(_) => parse_db(&words[3..], variables)?,
+        None => 0.0,
+    };
+    Ok(Filter {
+        kind,
+        frequency,
+        q,
+        gain,
+    })
+}
+
+/// What an effect does to the wave it is attached to.
+#[derive(Clone, Debug)]
+pub enum Processor {
+    Compressor(Compressor),
+    Gate(Gate),
+    Filter(Filter),
+}
+
+/// An effect attached to a voice or to the master bus.
+#[derive(Clone, Debug)]
+pub struct Effect {
+    pub processor: Processor,
+    /// Name of the voice whose level drives the effect instead of the wave it processes.
+    pub sidechain: Option<String>,
+}
+
+impl Effect {
+    /// Processes `wave`. `sidechains` holds the waves of the voices effects take as
+    /// sidechain, by name, before they go through their own effects.
+    pub fn apply(&self, wave: &mut AudioWave, sidechains: &HashMap<String, AudioWave>) {
+        let sidechain = self
+            .sidechain
+            .as_ref()
+            .and_then(|name| sidechains.get(name));
+        match &self.processor {
+            Processor::Compressor(c) => wave.compress(c, sidechain),
+            Processor::Gate(g) => wave.gate(g, sidechain),
+            Processor::Filter(f) => wave.filter(f),
+        }
+    }
+}
+
+/// Parses an effect line, with positional settings that can be left out from the end and
+/// optionally `sidechain <voice>` at the end:
+///
+/// - `compressor <threshold dB> <ratio> [attack] [release] [makeup dB]`, with 10 ms of
+///   attack, 100 ms of release and no makeup gain by default.
+/// - `gate <threshold dB> [attack] [release]`, with 1 ms of attack and 100 ms of release by
+///   default.
+/// - `filter <kind> <frequency> [q] [gain]`, as in `parse_filter`, which can't be sidechained.
+///
+/// Returns `None` if the line isn't an effect.
+pub fn parse_effect(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Option<Effect>, String> {
+    let Some(kind) = words.first() else {
+        return Ok(None);
+    };
+    if !["compressor", "gate", "filter"].contains(&kind.as_str()) {
+        return Ok(None);
+    }
+    let (settings, sidechain) = match words.iter().position(|w| w == "sidechain") {
+        Some(i) if i == words.len() - 2 => (&words[1..i], Some(words[i + 1].clone())),
+        Some(_) => return Err("Expected 'sidechain <voice>' at the end of the line".to_owned()),
+        None => (&words[1..], None),
+    };
+    let db = |i: usize| parse_db(&settings[i..i + 1], variables);
+    let time = |i: usize, default: Float| match settings.get(i) {
+        Some(w) => humanize::parse_time(w, variables).map(Float::abs),
+        None => Ok(default),
+    };
+    let processor = match kind.as_str() {
+        "filter" => {
+            if sidechain.is_some() {
+                return Err("Filters can't be sidechained".to_owned());
+            }
+            Processor::Filter(parse_filter(settings, variables)?)
+        }
+        "compressor" => {
+            if !(2..=5).contains(&settings.len()) {
+                return Err(
+                    "Expected 'compressor <threshold> <ratio> [attack] [release] [makeup]'"
+                        .to_owned(),
+                );
+            }
+            let ratio = expr::evaluate(&settings[1], variables)?;
+            if ratio < 1.0 {
+                return Err(format!("'{}' is not a valid compression ratio", ratio));
+            }
+            Processor::Compressor(Compressor {
+                threshold: db(0)?,
+                ratio,
+                attack: time(2, 0.01)?,
+                release: time(3, 0.1)?,
+                makeup: if settings.len() > 4 { db(4)? } else { 0.0 },
+            })
+        }
+        _ => {
+            if !(1..=3).contains(&settings.len()) {
+                return Err("Expected 'gate <threshold> [attack] [release]'".to_owned());
+            }
+            Processor::Gate(Gate {
+                threshold: db(0)?,
+                attack: time(1, 0.001)?,
+                release: time(2, 0.1)?,
+            })
+        }
+    };
+    Ok(Some(Effect {
+        processor,
+        sidechain,
+    }))
+}
+
+/// How the master bus is scaled before export.
+///
+/// Normalizing is a stage of the master bus applied when the score is rendered, not when the
+/// wave is exported, so that the soft clipping and the limiter after it work on the level the
+/// mix is brought to. Without a `master normalize` line, it leaves samples untouched.
+#[derive(Clone, Copy, Debug)]
+pub enum Normalization {
+    /// Brings the peak to the given level, in dBFS.
+    Peak(Float),
+    /// Brings the integrated loudness to the given level, in LUFS.
+    Loudness(Float),
+}
+
+/// Settings of the bus every voice is mixed into, given by `master` lines:
+///
+/// - `master gain <dB>`: gain applied to the mix.
+/// - `master eq <kind> <frequency> [q] [gain]`: adds a band to the equalizer applied after the
+///   gain, with the settings of `parse_filter`.
+/// - `master compressor ...`, `master gate ...` or `master filter ...`: effects applied after
+///   the equalizer, in order.
+/// - `master normalize peak <dB>` or `master normalize loudness <LUFS>`: scales the mix so its
+///   peak or its integrated loudness reaches the given level, after the effects and before the
+///   clipping and the limiter.
+/// - `master normalize off`: leaves samples untouched, which is the default.
+/// - `master clip tanh` or `master clip cubic`: rounds off loud samples, after normalizing.
+///   Off by default, or with `master clip off`.
+/// - `master limiter [ceiling <dB>] [lookahead <time>] [release <time>]`: limits the mix last,
+///   so it never goes above the ceiling. Omitted settings default to a ceiling at 0 dBFS, 5 ms
+///   of lookahead and 50 ms of release. Off by default, or with `master limiter off`, so the
+///   samples of a score without `master` lines are only changed by the voices' gains.
+///
+/// They can be written in any voice, and are read before the voices are played, so they can't
+/// use variables.
+#[derive(Clone, Debug)]
+pub struct Master {
+    gain: Float,
+    eq: Equalizer,
+    pub effects: Vec<Effect>,
+    normalization: Option<Normalization>,
+    clip: Option<SoftClip>,
+    limiter: Option<Limiter>,
+}
+
+impl Master {
+    pub fn new() -> Self {
+        Master {
+            gain: 1.0,
+            eq: Equalizer::default(),
+            effects: Vec::new(),
+            normalization: None,
+            clip: None,
+            limiter: None,
+        }
+    }
+
+    /// Applies the words of a `master` line following `master`.
+    pub fn apply(&mut self, words: &[String]) -> Result<(), String> {
+        let variables = HashMap::new();
+        let setting = words.first().ok_or("Missing master setting")?;
+        match setting.as_str() {
+            "gain" => self.gain = db_to_gain(parse_db(&words[1..], &variables)?),
+            "eq" => self.eq.bands.push(parse_filter(&words[1..], &variables)?),
+            "normalize" => {
+                let kind = words.get(1).ok_or("Missing normalization")?;
+                self.normalization = match kind.as_str() {
+                    "off" => None,
+                    "peak" => Some(Normalization::Peak(parse_db(&words[2..], &variables)?)),
+                    "loudness" => Some(Normalization::Loudness(parse_db(&words[2..], &variables)?)),
+                    other => return Err(format!("Unknown normalization '{}'", other)),
+                };
+            }
+            "clip" => {
+                let curve = words.get(1).ok_or("Missing clipping curve")?;
+                self.clip = match curve.as_str() {
+                    "off" => None,
+                    "tanh" => Some(SoftClip::Tanh),
+                    "cubic" => Some(SoftClip::Cubic),
+                    other => return Err(format!("Unknown clipping curve '{}'", other)),
+                };
+            }
+            "limiter" => self.limiter = parse_limiter(&words[1..], &variables)?,
+            other => match parse_effect(words, &variables)? {
+                Some(effect) => self.effects.push(effect),
+                None => return Err(format!("Unknown master setting '{}'", other)),
+            },
+        }
+        Ok(())
+    }
+
+    /// Processes the mix of every voice, `sidechains` being as in `Effect::apply`.
+    pub fn process(&self, wave: &mut AudioWave, sidechains: &HashMap<String, AudioWave>) {
+        wave.apply_gain(self.gain);
+        wave.equalize(&self.eq);
+        for effect in &self.effects {
+            effect.apply(wave, sidechains);
+        }
+        match self.normalization {
+            Some(Normalization::Peak(target)) => wave.normalize_peak(target),
+            Some(Normalization::Loudness(target)) => wave.normalize_loudness(target),
+            None => {}
+        }
+        if let Some(curve) = self.clip {
+            wave.soft_clip(curve);
+        }
+        if let Some(limiter) = &self.limiter {
+            wave.limit(limiter);
+        }
+    }
+}
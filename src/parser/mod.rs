@@ -1,7 +1,25 @@
-use crate::audiowave::AudioWave;
-use crate::definitions::Float;
+use crate::audiowave::{self, AudioWave};
+use crate::definitions::{to_f64, Float, SAMPLERATE};
 use crate::function::Function;
+use crate::instrument::{Instrument, Sampler};
+use crate::timeline::{self, Event, Timeline};
+use section::Section;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use transform::Transformation;
+
+mod expr;
+mod groove;
+mod humanize;
+mod include;
+mod mix;
+mod rhythm;
+mod section;
+mod synth;
+mod transform;
 
 #[derive(Debug)]
 pub enum Semitone {
@@ -11,7 +29,7 @@ pub enum Semitone {
 
 pub fn note_to_semitone(note: &String, default_octave: &Option<u8>) -> Option<Semitone> {
     if note == "_" {
-        Some(Semitone::Rest);
+        return Some(Semitone::Rest);
     };
 
     let default_octave = default_octave.unwrap_or(4);
@@ -36,13 +54,7 @@ pub fn note_to_semitone(note: &String, default_octave: &Option<u8>) -> Option<Se
         let mut state: u8 = 0; // 0 - sharps and flats
         let mut tempstring = "".to_owned();
         let mut isoctaverelative = false;
-        loop {
-            let current_char: char;
-            let extracting_char = modifiers.chars().nth(i);
-            match extracting_char {
-                Some(c) => current_char = c,
-                None => break,
-            }
+        while let Some(current_char) = modifiers.chars().nth(i) {
             i += 1;
             if state == 0 {
                 match current_char {
@@ -65,9 +77,8 @@ pub fn note_to_semitone(note: &String, default_octave: &Option<u8>) -> Option<Se
                             return None;
                         }
                         let val: Result<u8, _> = current_char.to_string().parse();
-                        match val {
-                            Ok(v) => s += 12.0 * (v - default_octave) as Float,
-                            Err(_) => (),
+                        if let Ok(v) = val {
+                            s += 12.0 * (v as Float - default_octave as Float)
                         }
                         break;
                     }
@@ -92,9 +103,8 @@ pub fn note_to_semitone(note: &String, default_octave: &Option<u8>) -> Option<Se
                             return None;
                         }
                         let val: Result<u8, _> = current_char.to_string().parse();
-                        match val {
-                            Ok(v) => s += 12.0 * (v - default_octave) as Float,
-                            Err(_) => (),
+                        if let Ok(v) = val {
+                            s += 12.0 * (v as Float - default_octave as Float)
                         }
                         break;
                     }
@@ -108,24 +118,50 @@ pub fn note_to_semitone(note: &String, default_octave: &Option<u8>) -> Option<Se
     Some(Semitone::Semitone(s))
 }
 
+/// Returns whether a word is a note name, a rest or a frequency literal.
+pub fn is_pitch(word: &String) -> bool {
+    match word.strip_suffix("Hz") {
+        Some(hz) => hz.parse::<Float>().is_ok(),
+        None => note_to_semitone(word, &Some(4)).is_some(),
+    }
+}
+
 pub fn get_freq_value(string: &String, octave: &u8, tuning: &Float) -> Result<Float, String> {
     if string.ends_with("Hz") {
         match string.replace("Hz", "").parse::<Float>() {
-            Ok(v) => return Ok(v),
-            Err(e) => return Err(format!("Error: '{}' is not a valid number", string)),
-        };
+            Ok(v) => Ok(v),
+            Err(_) => Err(format!("Error: '{}' is not a valid number", string)),
+        }
     } else {
         match note_to_semitone(string, &Some(*octave)) {
             Some(v) => match v {
                 Semitone::Semitone(x) => Ok(tuning * (2.0 as Float).powf(x / (12 as Float))),
                 Semitone::Rest => Ok(0.0),
             },
-            None => return Err(format!("Could not understand '{}' as a note name", string)),
+            None => Err(format!("Could not understand '{}' as a note name", string)),
         }
     }
 }
 
-pub fn split_by_whitespace(text: &String) -> Vec<String> {
+/// Splits the words of a line that takes time into the words before its duration and the
+/// duration, if it has one. The duration is everything after the notes, so it can be an
+/// expression with spaces like in `C | E beat * 3/2`. Glissandos have two notes before it,
+/// and trills two notes and a number of parts.
+pub fn split_duration(words: &[String]) -> (&[String], Option<String>) {
+    let notes = match words.first().map(|w| w.as_str()) {
+        Some("glissando") => 3,
+        Some("trill") => 4,
+        _ => words
+            .iter()
+            .position(|w| w != "|" && !is_pitch(w))
+            .unwrap_or(words.len()),
+    }
+    .min(words.len());
+    let duration = (notes < words.len()).then(|| words[notes..].join(" "));
+    (&words[..notes], duration)
+}
+
+pub fn split_by_whitespace(text: &str) -> Vec<String> {
     let mut result = Vec::new();
     let mut current_word = String::new();
 
@@ -148,11 +184,11 @@ pub fn split_by_whitespace(text: &String) -> Vec<String> {
     result
 }
 
-pub fn str_is_whitespace_or_empty(s: &String) -> bool {
+pub fn str_is_whitespace_or_empty(s: &str) -> bool {
     if s.is_empty() {
         return true;
     }
-    for ch in s.chars().into_iter() {
+    for ch in s.chars() {
         if !ch.is_whitespace() {
             return false;
         }
@@ -160,13 +196,67 @@ pub fn str_is_whitespace_or_empty(s: &String) -> bool {
     true
 }
 
+/// A line of a voice along with how long it takes to play and how loud its notes are.
+#[derive(Clone)]
+pub struct TimedLine {
+    pub line: String,
+    /// How long the line lasts, in samples.
+    pub samples: usize,
+    pub velocity: Float,
+    /// How many samples early (negative) or late the line starts, without moving the next one.
+    pub offset: isize,
+}
+
+/// Parses the arguments of a `bpm` line, which must give a finite and positive tempo.
+fn parse_bpm(words: &[String], variables: &HashMap<String, Float>) -> Result<Float, String> {
+    let bpm = expr::evaluate(&words.join(" "), variables)?;
+    if !(bpm > 0.0 && bpm.is_finite()) {
+        return Err(format!("'{}' is not a valid tempo", words.join(" ")));
+    }
+    Ok(bpm)
+}
+
+/// Moves `time`, in seconds, forward by `seconds` and returns how many samples that takes.
+/// Rounding the time reached rather than each length keeps lengths from adding up errors.
+fn advance_samples(time: &mut f64, seconds: f64, samplerate: u32) -> usize {
+    let start = (*time * samplerate as f64).round() as usize;
+    *time += seconds;
+    (*time * samplerate as f64).round() as usize - start
+}
+
+fn seconds_to_samples(seconds: Float, samplerate: u32) -> isize {
+    (to_f64(seconds) * samplerate as f64).round() as isize
+}
+
+/// What happened when playing a voice up to its next stopping point.
+pub enum Step {
+    /// A line that takes time was played, making these sounds (none for a rest).
+    Played(Vec<Event>),
+    /// A sync point was reached.
+    Synced(String),
+    /// The voice waits for a sync point before going on.
+    Waiting,
+    /// Every line has been played.
+    Finished,
+}
+
 #[derive(Clone)]
 pub enum VoiceContent {
     Raw(Vec<String>),
-    Processed(Vec<(String, Float)>),
+    Processed(Vec<TimedLine>),
 }
 
 #[derive(Clone)]
+/// The part of a voice's state that `state push` saves.
+struct SavedState {
+    bpm: Float,
+    tuning: Float,
+    default_duration: Float,
+    default_octave: u8,
+    intensity: Float,
+    instrument: Arc<Instrument>,
+}
+
 pub struct Voice {
     pub contents: VoiceContent,
     bpm: Float,
@@ -174,6 +264,29 @@ pub struct Voice {
     default_duration: Float,
     default_octave: u8,
     intensity: Float,
+    /// Name other voices refer to the voice by, set by a `voice <name>` line.
+    pub name: Option<String>,
+    /// Gain the whole voice is mixed with, set by a `gain <dB>` line anywhere in it.
+    pub gain: Float,
+    /// Effects the whole voice goes through before its gain, in order.
+    pub effects: Vec<mix::Effect>,
+    /// Instrument playing the next notes, shared with the events already played with it.
+    instrument: Arc<Instrument>,
+    /// Directory the files named in the voice are relative to.
+    directory: PathBuf,
+    /// Files read so far, shared with the other voices of the score.
+    files: Rc<RefCell<synth::Files>>,
+    variables: HashMap<String, Float>,
+    transforms: Vec<Vec<Transformation>>,
+    /// States saved by `state push` lines, restored by `state pop`.
+    saved: Vec<SavedState>,
+    /// Bar the voice is in, from 1, or 0 before it starts.
+    bar: u32,
+    samplerate: u32,
+    /// Index of the next processed line to play.
+    cursor: usize,
+    /// Sample at which the next line starts.
+    pub position: usize,
     pub waiting: Option<String>,
 }
 impl Voice {
@@ -184,296 +297,458 @@ impl Voice {
             default_duration: 1.0,
             default_octave: 4,
             intensity: 1.0,
+            name: None,
+            gain: 1.0,
+            effects: Vec::new(),
+            instrument: Arc::new(Instrument::default()),
+            directory: PathBuf::from("."),
+            files: Rc::default(),
+            variables: HashMap::new(),
+            transforms: Vec::new(),
+            saved: Vec::new(),
+            bar: 0,
+            samplerate: SAMPLERATE,
+            cursor: 0,
+            position: 0,
             waiting: None,
             contents: VoiceContent::Raw(vec!["".to_owned()]),
         }
     }
-    pub fn get_time(&mut self) {
-        let content: Vec<String>;
-        match &self.contents {
-            VoiceContent::Raw(r) => content = r.to_vec(),
-            VoiceContent::Processed(_) => return,
-        }
-        let mut processed: Vec<(String, Float)> = Vec::new();
+    /// Gets the frequency of a note or frequency literal, with the active transformations applied.
+    fn get_pitch(&self, word: &String) -> Result<Float, String> {
+        let freq = get_freq_value(word, &self.default_octave, &self.tuning)?;
+        transform::apply_to_freq(freq, &self.transforms, |axis| {
+            get_freq_value(axis, &self.default_octave, &self.tuning)
+        })
+    }
+    pub fn get_time(&mut self) -> Result<(), String> {
+        let content = match &mut self.contents {
+            VoiceContent::Raw(r) => std::mem::take(r),
+            VoiceContent::Processed(_) => return Ok(()),
+        };
+        let mut processed: Vec<TimedLine> = Vec::new();
+        let mut clock = groove::Clock::default();
+        let mut humanizer: Option<humanize::Humanizer> = None;
+        let mut bpm = self.bpm;
+        let mut default_duration = self.default_duration;
+        let mut variables = self.variables.clone();
+        let mut transforms: Vec<Vec<Transformation>> = Vec::new();
+        // bpm and default duration saved by `state push` lines
+        let mut saved: Vec<(Float, Float)> = Vec::new();
+        let mut time_signature: Option<(u32, u32)> = None;
+        let mut bar_number: u32 = 1;
+        // beats played in the current bar
+        let mut bar_beats: Float = 0.0;
+        // seconds since the start of the voice
+        let mut time: f64 = 0.0;
         for line in content {
             let words = split_by_whitespace(&line);
-            let possibly_a_note = &words[1];
             let nullstr = &("".to_owned());
-            let lastword = words.last().unwrap_or(nullstr);
-            let mut seconds: Float = 0.0;
+            let possibly_a_note = words.first().unwrap_or(nullstr);
+            let mut samples: usize = 0;
+            let mut velocity: Float = 1.0;
+            let mut offset: isize = 0;
+            let invalid = |e: String| format!("Invalid syntax at line: {}\n{}", line, e);
 
-            if line.starts_with("glissando")
+            if words.len() >= 2 && words[0] == "let" {
+                let (name, value) = expr::parse_let(&words[1..], &variables).map_err(invalid)?;
+                variables.insert(name, value);
+            } else if words.len() >= 2 && words[0] == "bpm" {
+                bpm = parse_bpm(&words[1..], &variables).map_err(invalid)?;
+            } else if words.len() >= 2 && words[0] == "duration" {
+                default_duration =
+                    rhythm::parse_duration(&words[1..].join(" "), &variables).map_err(invalid)?;
+            } else if words.len() >= 2 && words[0] == "transform" {
+                if words[1] == "push" {
+                    transforms.push(
+                        transform::parse_transformations(&words[2..], &variables)
+                            .map_err(invalid)?,
+                    );
+                } else {
+                    transforms.pop();
+                }
+            } else if words.len() >= 2 && words[0] == "state" {
+                if words[1] == "push" {
+                    saved.push((bpm, default_duration));
+                } else if let Some(state) = saved.pop() {
+                    (bpm, default_duration) = state;
+                }
+            } else if words.len() >= 2 && words[0] == "swing" {
+                let ratio = expr::evaluate(&words[1..].join(" "), &variables).map_err(invalid)?;
+                clock.groove = if ratio == 0.5 {
+                    None
+                } else {
+                    Some(groove::Groove::swing(0.5, ratio).map_err(invalid)?)
+                };
+            } else if words.len() >= 2 && words[0] == "groove" {
+                clock.groove = groove::parse(&words[1..], &variables).map_err(invalid)?;
+            } else if words.len() >= 2 && words[0] == "humanize" {
+                humanizer = humanize::parse(&words[1..], &variables).map_err(invalid)?;
+            } else if words.len() >= 2 && words[0] == "gain" {
+                self.gain =
+                    audiowave::db_to_gain(mix::parse_db(&words[1..], &variables).map_err(invalid)?);
+            } else if words.len() >= 2 && words[0] == "voice" {
+                if words.len() > 2 || !expr::is_valid_name(&words[1]) {
+                    return Err(invalid(format!(
+                        "'{}' is not a valid voice name",
+                        words[1..].join(" ")
+                    )));
+                }
+                self.name = Some(words[1].clone());
+            } else if let Some(effect) = mix::parse_effect(&words, &variables).map_err(invalid)? {
+                self.effects.push(effect);
+            } else if words.len() >= 2 && words[0] == "time" {
+                if bar_beats != 0.0 {
+                    return Err(invalid(
+                        "The time signature can only change at the start of a bar".to_owned(),
+                    ));
+                }
+                time_signature =
+                    Some(rhythm::parse_time_signature(&words[1..].join("")).map_err(invalid)?);
+            } else if words[0] == "bar" {
+                if let Some(signature) = time_signature {
+                    let expected = rhythm::bar_length(signature);
+                    if (bar_beats - expected).abs() > 1e-3 {
+                        return Err(format!(
+                            "Error: bar {} lasts {} beats, but bars in {}/{} last {} beats",
+                            bar_number, bar_beats, signature.0, signature.1, expected
+                        ));
+                    }
+                }
+                bar_number += 1;
+                bar_beats = 0.0;
+            } else if words[0] == "tuplet" {
+                let factor = transform::tempo_factor(&transforms);
+                let notes = rhythm::parse_tuplet(&words[1..], default_duration, &variables)
+                    .map_err(invalid)?;
+                for (notes, beats) in notes {
+                    bar_beats += beats / factor;
+                    let offset = humanizer.as_mut().map_or(0.0, |h| h.next_offset());
+                    let (beats, accent) = clock.advance(beats / factor);
+                    let velocity = match &mut humanizer {
+                        Some(h) => h.next_velocity(accent),
+                        None => accent,
+                    };
+                    let seconds = to_f64(beats) * 60.0 / to_f64(bpm);
+                    processed.push(TimedLine {
+                        line: notes,
+                        samples: advance_samples(&mut time, seconds, self.samplerate),
+                        velocity,
+                        offset: seconds_to_samples(offset, self.samplerate),
+                    });
+                }
+                continue;
+            } else if line.starts_with("glissando")
                 || line.starts_with("trill")
-                || note_to_semitone(possibly_a_note, &Some(4)).is_some()
+                || is_pitch(possibly_a_note)
             {
-                let _lastword = lastword;
-                match _lastword.parse::<Float>() {
-                    Ok(v) => seconds = v * 60.0 / self.bpm,
-                    Err(_) => seconds = self.default_duration * 60.0 / self.bpm,
-                }
+                let beats = match split_duration(&words).1 {
+                    Some(d) => rhythm::parse_duration(&d, &variables).map_err(invalid)?,
+                    None => default_duration,
+                };
+                let factor = transform::tempo_factor(&transforms);
+                bar_beats += beats / factor;
+                let humanized_offset = humanizer.as_mut().map_or(0.0, |h| h.next_offset());
+                let (beats, accent) = clock.advance(beats / factor);
+                let seconds = to_f64(beats) * 60.0 / to_f64(bpm);
+                samples = advance_samples(&mut time, seconds, self.samplerate);
+                velocity = match &mut humanizer {
+                    Some(h) => h.next_velocity(accent),
+                    None => accent,
+                };
+                offset = seconds_to_samples(humanized_offset, self.samplerate);
             }
-            processed.push((line, seconds));
+            processed.push(TimedLine {
+                line,
+                samples,
+                velocity,
+                offset,
+            });
         }
         self.contents = VoiceContent::Processed(processed);
+        Ok(())
     }
-    pub fn get_audio(&mut self) -> Result<Option<(Option<AudioWave>, Option<String>)>, String> {
+    /// Plays the voice until a line that takes time, a sync point or a wait.
+    pub fn get_audio(&mut self) -> Result<Step, String> {
         if self.waiting.is_some() {
-            return Ok(None);
+            return Ok(Step::Waiting);
         }
-        let mut audio: AudioWave = AudioWave::new(
-            &Function::Const(0.0),
-            &Function::Const(0.0),
-            &0.0,
-            None,
-            None,
-            None,
-            None,
-        )
-        .expect("Should be able to create empty wave");
-        match &mut self.contents {
-            VoiceContent::Raw(_) => return Ok(None),
-            VoiceContent::Processed(ref mut p) => {
-                if p.is_empty() {
-                    return Err("Empty".to_owned());
-                }
-                let clonep = p.clone();
-                for i in 0..p.len() {
-                    let line = clonep[i].clone();
-                    p.remove(0);
-                    let words = split_by_whitespace(&line.0);
-                    if words[0] == "bpm" {
-                        match words[1].parse::<Float>() {
-                            Ok(v) => self.bpm = v,
-                            Err(e) => {
-                                return Err(format!("Invalid syntax at line: {}\n{}", line.0, e))
-                            }
-                        };
-                    } else if words[0] == "tuning" {
-                        match words[1].parse::<Float>() {
-                            Ok(v) => self.tuning = v,
-                            Err(e) => {
-                                return Err(format!("Invalid syntax at line: {}\n{}", line.0, e))
-                            }
-                        };
-                    } else if words[0] == "duration" {
-                        match words[1].parse::<Float>() {
-                            Ok(v) => self.default_duration = v,
-                            Err(e) => {
-                                return Err(format!("Invalid syntax at line: {}\n{}", line.0, e))
-                            }
-                        };
-                    } else if words[0] == "octave" {
-                        match words[1].parse::<u8>() {
-                            Ok(v) => self.default_octave = v,
-                            Err(e) => {
-                                return Err(format!("Invalid syntax at line: {}\n{}", line.0, e))
-                            }
-                        };
-                    } else if words[0] == "intensity" {
-                        match words[1].parse::<Float>() {
-                            Ok(v) => self.intensity = v,
-                            Err(e) => {
-                                return Err(format!("Invalid syntax at line: {}\n{}", line.0, e))
-                            }
-                        };
-                    } else if words[0] == "wait" {
-                        self.waiting = Some(words[1].clone());
-                        return Ok(Some((Some(audio), None)));
-                    } else if words[0] == "sync" {
-                        return Ok(Some((Some(audio), Some(words[1].clone()))));
-                    } else if words[0] == "glissando" {
-                        let line_replicate = line.clone();
-                        let line_replicate2 = line.clone();
-                        let line_replicate3 = line;
-                        let mut first_note: Float = 0.0;
-                        let mut last_note: Float = 0.0;
-                        match get_freq_value(&words[1], &self.default_octave, &self.tuning) {
-                            Ok(v) => {
-                                if v == 0.0 {
-                                    return Err(
-                                        "Error: rests cannot be part of a glissando".to_owned()
-                                    );
-                                }
-                                first_note = v;
-                            }
-                            Err(e) => return Err(e),
-                        }
-                        match get_freq_value(&words[2], &self.default_octave, &self.tuning) {
-                            Ok(v) => {
-                                if v == 0.0 {
-                                    return Err(
-                                        "Error: rests cannot be part of a glissando".to_owned()
-                                    );
-                                }
-                                last_note = v;
-                            }
-                            Err(e) => return Err(e),
-                        }
-                        let f = {
-                            let _first_note = first_note;
-                            let _last_note = last_note;
-                            let _line = line_replicate;
-                            move |t: Float| -> Float {
-                                _first_note * ((_last_note / _first_note).powf(t / _line.1))
-                            }
-                        };
-                        match AudioWave::new(
-                            &Function::Function(Box::new(f)),
-                            &Function::Const(self.intensity),
-                            &line_replicate2.1,
-                            None,
-                            None,
-                            None,
-                            None,
-                        ) {
-                            Some(v) => {
-                                audio.clone().append(v, Some(1.0));
-                                return Ok(Some((Some(audio), None)));
-                            }
-                            None => {
-                                return Err(format!(
-                                    "Error: failed to generate wave corresponding to line: {}",
-                                    line_replicate3.0
-                                ))
-                            }
-                        };
-                    } else if words[0] == "trill" {
-                        let mut first_note: Float = 0.0;
-                        let mut second_note: Float = 0.0;
-                        match get_freq_value(&words[1], &self.default_octave, &self.tuning) {
-                            Ok(v) => {
-                                first_note = v;
-                            }
-                            Err(e) => return Err(e),
-                        }
-                        match get_freq_value(&words[2], &self.default_octave, &self.tuning) {
-                            Ok(v) => {
-                                second_note = v;
-                            }
-                            Err(e) => return Err(e),
-                        }
-                        let mut parts: u32 = 1;
-                        match &words[3].parse::<u32>() {
-                            Ok(v) => {
-                                parts = *v;
-                            }
-                            Err(e) => {
-                                return Err(format!("Invalid syntax at line: {}\n{}", line.0, e))
-                            }
-                        };
-                        let first_audio = AudioWave::new(
-                            &Function::Const(first_note),
-                            &Function::Const(self.intensity),
-                            &(line.1 / (parts as Float)),
-                            None,
-                            None,
-                            None,
-                            None,
-                        );
-                        let second_audio = AudioWave::new(
-                            &Function::Const(second_note),
-                            &Function::Const(self.intensity),
-                            &(line.1 / (parts as Float)),
-                            None,
-                            None,
-                            None,
-                            None,
+        if let VoiceContent::Raw(_) = self.contents {
+            self.get_time()?;
+        }
+        if self.bar == 0 {
+            self.bar = 1;
+            return Ok(Step::Synced("bar 1".to_owned()));
+        }
+        let lines = match &self.contents {
+            VoiceContent::Processed(p) => p,
+            VoiceContent::Raw(_) => unreachable!("the voice was just timed"),
+        };
+        while let Some(line) = lines.get(self.cursor) {
+            self.cursor += 1;
+            let words = split_by_whitespace(&line.line);
+            let invalid = |e: String| format!("Invalid syntax at line: {}\n{}", line.line, e);
+            let Some(first) = words.first() else {
+                continue;
+            };
+            match first.as_str() {
+                "let" => {
+                    let (name, value) =
+                        expr::parse_let(&words[1..], &self.variables).map_err(invalid)?;
+                    self.variables.insert(name, value);
+                }
+                "bpm" => self.bpm = parse_bpm(&words[1..], &self.variables).map_err(invalid)?,
+                "tuning" => {
+                    self.tuning =
+                        expr::evaluate(&words[1..].join(" "), &self.variables).map_err(invalid)?
+                }
+                "duration" => {
+                    self.default_duration =
+                        rhythm::parse_duration(&words[1..].join(" "), &self.variables)
+                            .map_err(invalid)?
+                }
+                "octave" => {
+                    let v =
+                        expr::evaluate(&words[1..].join(" "), &self.variables).map_err(invalid)?;
+                    if v.fract() != 0.0 || !(0.0..=9.0).contains(&v) {
+                        return Err(invalid(format!("'{}' is not a valid octave", v)));
+                    }
+                    self.default_octave = v as u8;
+                }
+                "intensity" => {
+                    self.intensity =
+                        expr::evaluate(&words[1..].join(" "), &self.variables).map_err(invalid)?
+                }
+                "transform" => {
+                    if words.get(1).map(|w| w.as_str()) == Some("push") {
+                        self.transforms.push(
+                            transform::parse_transformations(&words[2..], &self.variables)
+                                .map_err(invalid)?,
                         );
-                        let first_audio_as_some: AudioWave;
-                        let second_audio_as_some: AudioWave;
-                        match first_audio {
-                            Some(v) => {
-                                first_audio_as_some = v;
-                            }
-                            None => {
-                                return Err(format!(
-                                    "Error: failed to generate wave corresponding to line: {}",
-                                    line.0
-                                ))
-                            }
-                        }
-                        match second_audio {
-                            Some(v) => {
-                                second_audio_as_some = v;
-                            }
-                            None => {
-                                return Err(format!(
-                                    "Error: failed to generate wave corresponding to line: {}",
-                                    line.0
-                                ))
-                            }
-                        }
-
-                        let mut is_first = true;
-                        for _ in 0..parts {
-                            if is_first {
-                                audio = audio.clone().append(first_audio_as_some.clone(), Some(1.0)).expect("Waves generated by this module should always be compatible");
-                            } else {
-                                audio = audio.clone().append(second_audio_as_some.clone(), Some(1.0)).expect("Waves generated by this module should always be compatible");
-                            }
-                            is_first = !is_first;
-                        }
-                        return Ok(Some((Some(audio), None)));
                     } else {
-                        let mut line_audio = AudioWave::new(
-                            &Function::Const(0.0),
-                            &Function::Const(0.0),
-                            &0.0,
-                            None,
-                            None,
-                            None,
-                            None,
-                        )
-                        .expect("Should be able to create empty wave");
-                        for i in 0..line.0.len() {
-                            if words[i] == "|".to_owned() {
-                                continue;
-                            };
-                            match words[i].parse::<u32>() {
-                                Ok(_) => continue,
-                                Err(_) => (),
-                            }
-                            match get_freq_value(&words[i], &self.default_octave, &self.tuning) {
-                                Ok(v) => {
-                                    match AudioWave::new(&Function::Const(v), &Function::Const(self.intensity), &line.1, None, None, None, None){
-                                        Some(u) => line_audio = line_audio.clone().add(u).expect("Waves generated by this module should always be compatible"),
-                                        None => return Err(format!("Error: failed to generate wave corresponding to line: {}",line.0)),
-                                    };
-                                }
-                                Err(e) => {
-                                    return Err(format!(
-                                        "Error: failed to generate wave corresponding to line: {}",
-                                        line.0
-                                    ))
-                                }
-                            };
-                        }
-                        audio = audio
-                            .clone()
-                            .append(line_audio, Some(1.0))
-                            .expect("Waves generated by this module should always be compatible");
-                        return Ok(Some((Some(audio), None)));
+                        self.transforms.pop();
+                    }
+                }
+                "state" => {
+                    if words.get(1).map(|w| w.as_str()) == Some("push") {
+                        self.saved.push(SavedState {
+                            bpm: self.bpm,
+                            tuning: self.tuning,
+                            default_duration: self.default_duration,
+                            default_octave: self.default_octave,
+                            intensity: self.intensity,
+                            instrument: self.instrument.clone(),
+                        });
+                    } else if let Some(state) = self.saved.pop() {
+                        self.bpm = state.bpm;
+                        self.tuning = state.tuning;
+                        self.default_duration = state.default_duration;
+                        self.default_octave = state.default_octave;
+                        self.intensity = state.intensity;
+                        self.instrument = state.instrument;
                     }
                 }
+                "waveform" => {
+                    self.instrument = Arc::new(
+                        synth::parse_waveform(
+                            &words[1..],
+                            &self.variables,
+                            &self.directory,
+                            &mut self.files.borrow_mut(),
+                        )
+                        .map_err(invalid)?,
+                    )
+                }
+                "harmonics" => {
+                    self.instrument = Arc::new(
+                        synth::parse_harmonics(&words[1..], &self.variables).map_err(invalid)?,
+                    )
+                }
+                "sampler" => {
+                    let zone = synth::parse_zone(
+                        &words[1..],
+                        &self.variables,
+                        &self.directory,
+                        &mut self.files.borrow_mut(),
+                        |w| get_freq_value(w, &self.default_octave, &self.tuning),
+                        self.tuning,
+                    )
+                    .map_err(invalid)?;
+                    // zones add up until another instrument is picked
+                    let mut sampler = match self.instrument.as_ref() {
+                        Instrument::Sampler(sampler) => sampler.clone(),
+                        _ => Sampler::default(),
+                    };
+                    sampler.zones.push(zone);
+                    self.instrument = Arc::new(Instrument::Sampler(sampler));
+                }
+                "synth" => {
+                    self.instrument =
+                        Arc::new(synth::parse(&words[1..], &self.variables).map_err(invalid)?)
+                }
+                // already taken into account by `get_time`
+                "time" | "swing" | "groove" | "humanize" | "gain" | "voice" | "compressor"
+                | "gate" | "filter" => {}
+                "bar" => {
+                    self.bar += 1;
+                    return Ok(Step::Synced(format!("bar {}", self.bar)));
+                }
+                "wait" => {
+                    self.waiting = Some(words[1..].join(" "));
+                    return Ok(Step::Waiting);
+                }
+                "sync" => return Ok(Step::Synced(words[1..].join(" "))),
+                _ => {
+                    let events = self.play(line, &words)?;
+                    self.position += line.samples;
+                    return Ok(Step::Played(events));
+                }
+            }
+        }
+        Ok(Step::Finished)
+    }
+    /// Turns a line that takes time into events, starting where the voice currently is.
+    fn play(&self, line: &TimedLine, words: &[String]) -> Result<Vec<Event>, String> {
+        let start = (self.position as isize + line.offset).max(0) as usize;
+        let velocity = self.intensity * line.velocity;
+        let mut events: Vec<Event> = Vec::new();
+        let invalid = |e: &str| format!("Invalid syntax at line: {}\n{}", line.line, e);
+        if words[0] == "glissando" {
+            let (Some(first_note), Some(last_note)) = (words.get(1), words.get(2)) else {
+                return Err(invalid("Expected 'glissando <from> <to> [duration]'"));
+            };
+            let first_note = self.get_pitch(first_note)?;
+            let last_note = self.get_pitch(last_note)?;
+            if first_note == 0.0 || last_note == 0.0 {
+                return Err("Error: rests cannot be part of a glissando".to_owned());
+            }
+            let seconds = line.samples as Float / self.samplerate as Float;
+            let f = move |t: Float| -> Float {
+                first_note * ((last_note / first_note).powf(t / seconds))
+            };
+            events.push(Event {
+                start,
+                length: line.samples,
+                freq: Function::Function(Box::new(f)),
+                amp: Function::Const(velocity),
+                velocity,
+                instrument: Arc::clone(&self.instrument),
+            });
+        } else if words[0] == "trill" {
+            let (Some(first_note), Some(second_note), Some(parts)) =
+                (words.get(1), words.get(2), words.get(3))
+            else {
+                return Err(invalid(
+                    "Expected 'trill <note> <other note> <parts> [duration]'",
+                ));
+            };
+            let first_note = self.get_pitch(first_note)?;
+            let second_note = self.get_pitch(second_note)?;
+            let parts = match expr::evaluate(parts, &self.variables) {
+                Ok(v) if v >= 1.0 => v as usize,
+                Ok(v) => return Err(invalid(&format!("'{}' is not a valid number of parts", v))),
+                Err(e) => return Err(invalid(&e)),
+            };
+            let boundary = |i: usize| line.samples * i / parts;
+            for i in 0..parts {
+                let note = if i % 2 == 0 { first_note } else { second_note };
+                events.push(Event {
+                    start: start + boundary(i),
+                    length: boundary(i + 1) - boundary(i),
+                    freq: Function::Const(note),
+                    amp: Function::Const(velocity),
+                    velocity,
+                    instrument: Arc::clone(&self.instrument),
+                });
+            }
+        } else {
+            if !is_pitch(&words[0]) {
+                return Err(invalid(&format!("Unknown directive '{}'", words[0])));
+            }
+            let (notes, _) = split_duration(words);
+            let mut freqs: Vec<Float> = Vec::new();
+            for note in notes {
+                if note == "|" {
+                    continue;
+                };
+                let freq = self.get_pitch(note).map_err(|_| {
+                    format!(
+                        "Error: failed to generate wave corresponding to line: {}",
+                        line.line
+                    )
+                })?;
+                if freq != 0.0 {
+                    freqs.push(freq);
+                }
+            }
+            // the notes of a chord share the amplitude of a single note
+            let amp = velocity / freqs.len().max(1) as Float;
+            for freq in freqs {
+                events.push(Event {
+                    start,
+                    length: line.samples,
+                    freq: Function::Const(freq),
+                    amp: Function::Const(amp),
+                    velocity,
+                    instrument: Arc::clone(&self.instrument),
+                });
             }
         }
-        return Err("Unable to understand text".to_owned());
+        Ok(events)
     }
 }
 
 /// If syntax error is found, returns None
 /// Each element in the vector corresponds to one voice and each voice is split by lines
+///
+/// Sections are local to the voice that defines them, unless defined with `global section`,
+/// in which case every voice can jump to them. Sections can be defined inside other sections,
+/// sharing the scope of the outermost one. Defining a section again is only allowed with the
+/// same parameters and lines, so that files defining sections can be included several times.
+///
+/// `include "path";` lines are replaced by the contents of the file, relative to the
+/// current directory.
 pub fn preprocess(text: String) -> Result<Vec<Vec<String>>, String> {
-    // there is a bug here, still need to find out what it's causing it
-    let voices = text.split('%');
-    let mut chunks: Vec<Vec<String>> = Vec::new();
-    let mut sections: HashMap<String, Vec<String>, _> = HashMap::new();
-    let mut onsection = false;
-    let mut current_section: String = "".to_owned();
-    for voice in voices {
+    preprocess_in(text, Path::new("."), &mut Vec::new())
+}
+
+/// Same as `preprocess`, reading the score from a file and resolving includes relative to it.
+pub fn preprocess_file(path: &Path) -> Result<Vec<Vec<String>>, String> {
+    let canonical = match path.canonicalize() {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Error: cannot read '{}': {}", path.display(), e)),
+    };
+    let text = match std::fs::read_to_string(&canonical) {
+        Ok(t) => t,
+        Err(e) => return Err(format!("Error: cannot read '{}': {}", path.display(), e)),
+    };
+    let dir = canonical.parent().unwrap_or(Path::new(".")).to_path_buf();
+    preprocess_in(text, &dir, &mut vec![canonical])
+}
+
+/// A section whose `end` line hasn't been reached yet.
+struct OpenSection {
+    name: String,
+    is_global: bool,
+    /// The header line and the section read so far, when the section was already defined.
+    redefinition: Option<(String, Section)>,
+}
+
+fn preprocess_in(
+    text: String,
+    dir: &Path,
+    chain: &mut Vec<std::path::PathBuf>,
+) -> Result<Vec<Vec<String>>, String> {
+    let text = include::resolve_includes(&text, dir, chain)?;
+    let mut global_sections: HashMap<String, Section> = HashMap::new();
+    let mut voices: Vec<(Vec<String>, HashMap<String, Section>)> = Vec::new();
+    for voice in text.split('%') {
         let mut voicevec: Vec<String> = Vec::new();
+        let mut local_sections: HashMap<String, Section> = HashMap::new();
+        // sections being defined, innermost last
+        let mut open_sections: Vec<OpenSection> = Vec::new();
         for mut line in voice.split(';') {
-            if str_is_whitespace_or_empty(&line.to_string()) {
+            if str_is_whitespace_or_empty(line) {
                 continue;
             }
             line = line.trim_matches(char::is_whitespace);
@@ -481,186 +756,229 @@ pub fn preprocess(text: String) -> Result<Vec<Vec<String>>, String> {
             if line.starts_with("$") {
                 continue;
             }
-            if line.starts_with("section") {
-                if onsection {
+            let first_word = line.split_whitespace().next().unwrap_or("");
+            let is_global = first_word == "global";
+            let header = if is_global {
+                line[6..].trim_start()
+            } else {
+                line
+            };
+            if header.split_whitespace().next() == Some("section") {
+                let (section_name, params, rest) = match section::parse_call(&header[7..]) {
+                    Ok(v) => v,
+                    Err(e) => return Err(format!("Invalid syntax on line: {}\n{}", line, e)),
+                };
+                if !rest.is_empty() {
                     return Err(format!(
-                        "Invalid syntax on line: {}\nAlready on a section",
-                        line
+                        "Invalid syntax on line: {}\nUnexpected '{}' after the section name",
+                        line,
+                        rest.join(" ")
                     ));
                 }
-                onsection = true;
-                let aux = split_by_whitespace(&line.to_string());
-                let section_name = aux[1].replace("", "");
-                if sections.contains_key(&section_name) {
-                    return Err(format!("Invalid syntax on line: {}\nSection '{}' already defined (or being defined)", line, section_name));
+                if let Err(e) = section::validate_params(&params) {
+                    return Err(format!("Invalid syntax on line: {}\n{}", line, e));
                 }
-                current_section = section_name.to_owned();
-                sections.insert(section_name, Vec::new());
-            } else if line.starts_with("end") {
-                if !onsection {
+                let is_global = is_global || open_sections.first().is_some_and(|s| s.is_global);
+                let sections = if is_global {
+                    &mut global_sections
+                } else {
+                    &mut local_sections
+                };
+                if open_sections
+                    .iter()
+                    .any(|s| s.name == section_name && s.is_global == is_global)
+                {
                     return Err(format!(
-                        "Invalid syntax on line: {}\nNo section to end",
-                        line
+                        "Invalid syntax on line: {}\nSection '{}' is being defined",
+                        line, section_name
                     ));
                 }
-                onsection = false;
-            } else if line.starts_with("jump") {
-                let aux = split_by_whitespace(&line.to_string());
-                let section_name = aux[1].replace("", "");
-                match sections.get(&section_name) {
-                    Some(v) => {
-                        let mut repetitions: u32 = 1;
-                        if aux.len() >= 3 {
-                            let rep: Result<u32, _> = aux[2].replace("", "").parse();
-                            match rep {
-                                Ok(u) => repetitions = u,
-                                Err(e) => {
-                                    return Err(format!("Invalid syntax on line: {}\n{}", line, e))
-                                }
-                            }
-                        }
-                        for _ in 0..repetitions {
-                            voicevec.extend(v.clone());
-                        }
-                    }
-                    None => {
-                        return Err(format!(
-                            "Error on line: {}\nNo section named '{}'",
-                            line, section_name
-                        ))
-                    }
-                }
-            } else if onsection {
-                if current_section.is_empty() {
+                let section = Section {
+                    params,
+                    lines: Vec::new(),
+                };
+                // a section can be defined again with the same lines, e.g. by a file
+                // included from several voices, which is checked when it ends
+                let redefinition = if sections.contains_key(&section_name) {
+                    Some((line.to_owned(), section))
+                } else {
+                    sections.insert(section_name.clone(), section);
+                    None
+                };
+                open_sections.push(OpenSection {
+                    name: section_name,
+                    is_global,
+                    redefinition,
+                });
+            } else if is_global {
+                return Err(format!(
+                    "Invalid syntax on line: {}\nOnly sections can be global",
+                    line
+                ));
+            } else if first_word == "end" {
+                let Some(ended) = open_sections.pop() else {
                     return Err(format!(
-                        "Error on line: {}\nCannot insert data on unnamed section",
+                        "Invalid syntax on line: {}\nNo section to end",
                         line
                     ));
+                };
+                if let Some((header, section)) = ended.redefinition {
+                    let sections = if ended.is_global {
+                        &global_sections
+                    } else {
+                        &local_sections
+                    };
+                    if sections.get(&ended.name) != Some(&section) {
+                        return Err(format!(
+                            "Invalid syntax on line: {}\nSection '{}' already defined differently",
+                            header, ended.name
+                        ));
+                    }
                 }
-                if let Some(x) = sections.get_mut(&current_section) {
-                    (*x).push(line.to_owned());
+            } else if let Some(current) = open_sections.last_mut() {
+                let sections = if current.is_global {
+                    &mut global_sections
+                } else {
+                    &mut local_sections
+                };
+                match &mut current.redefinition {
+                    Some((_, section)) => section.lines.push(line.to_owned()),
+                    None => {
+                        if let Some(x) = sections.get_mut(&current.name) {
+                            x.lines.push(line.to_owned());
+                        }
+                    }
                 }
             } else {
                 voicevec.push(line.to_owned());
             }
         }
-        chunks.push(voicevec);
+        if let Some(open) = open_sections.last() {
+            return Err(format!("Error: section '{}' is never ended", open.name));
+        }
+        voices.push((voicevec, local_sections));
+    }
+
+    let (voices, local_sections): (Vec<Vec<String>>, Vec<HashMap<String, Section>>) =
+        voices.into_iter().unzip();
+    let mut chunks: Vec<Vec<String>> = Vec::with_capacity(voices.len());
+    for (voicevec, local) in voices.iter().zip(&local_sections) {
+        let scope = section::Scope {
+            global: &global_sections,
+            local,
+            voices: &local_sections,
+        };
+        let expanded = section::expand(voicevec, &scope, &mut Vec::new())?;
+        chunks.push(section::expand_bar_jumps(expanded)?);
     }
     Ok(chunks)
 }
 
 pub struct Manager {
     voices: Vec<(Voice, bool)>,
-    voice_audios: Vec<AudioWave>,
-    time_elapsed: Vec<Float>,
-    point_flag: (String, Float),
+    timelines: Vec<Timeline>,
+    /// Sync points reached so far and the sample at which they were reached.
+    sync_points: HashMap<String, usize>,
+    /// Number of threads rendering the voices.
+    threads: usize,
 }
 
 impl Manager {
     pub fn new() -> Self {
         Manager {
             voices: vec![],
-            voice_audios: vec![],
-            time_elapsed: vec![],
-            point_flag: ("".to_owned(), 0.0),
+            timelines: vec![],
+            sync_points: HashMap::new(),
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
         }
     }
+    /// Sets the number of threads rendering the voices, 1 rendering them one after the other.
+    /// It only changes how fast scores are rendered, never how they sound.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+    /// Renders a score. Files it names are relative to the current directory.
     pub fn run(&mut self, text: String) -> Result<AudioWave, String> {
-        let mut vec: Vec<Vec<String>>;
-        match preprocess(text) {
-            Ok(v) => vec = v,
-            Err(e) => return Err(e),
-        };
+        self.render(preprocess(text)?, Path::new("."))
+    }
+    /// Renders a score read from a file. Files it names are relative to it.
+    pub fn run_file(&mut self, path: &Path) -> Result<AudioWave, String> {
+        let directory = path.parent().unwrap_or(Path::new("."));
+        self.render(preprocess_file(path)?, directory)
+    }
+    fn render(&mut self, vec: Vec<Vec<String>>, directory: &Path) -> Result<AudioWave, String> {
+        let mut master = mix::Master::new();
+        let files = Rc::new(RefCell::new(synth::Files::default()));
         for item in vec {
+            let (master_lines, lines): (Vec<String>, Vec<String>) = item
+                .into_iter()
+                .partition(|line| line.split_whitespace().next() == Some("master"));
+            for line in master_lines {
+                let words = split_by_whitespace(&line);
+                master
+                    .apply(&words[1..])
+                    .map_err(|e| format!("Invalid syntax at line: {}\n{}", line, e))?;
+            }
             let mut voice = Voice::new();
-            voice.contents = VoiceContent::Raw(item);
-            voice.get_time();
+            voice.contents = VoiceContent::Raw(lines);
+            voice.directory = directory.to_path_buf();
+            voice.files = files.clone();
+            voice.get_time()?;
             self.voices.push((voice, false));
-            self.time_elapsed.push(0.0);
-            self.voice_audios.push(
-                AudioWave::new(
-                    &Function::Const(0.0),
-                    &Function::Const(0.0),
-                    &0.0,
-                    None,
-                    None,
-                    None,
-                    None,
-                )
-                .expect("Should be able to create empty wave"),
-            );
+            self.timelines.push(Timeline::new());
         }
         loop {
-            let mut late_one = Float::INFINITY;
-            let mut late_one_index: usize = 0;
+            // the runnable voice that is the furthest behind, and its position
+            let mut late_one: Option<(usize, usize)> = None;
             let mut completed = true;
+            let mut released = false;
             for i in 0..self.voices.len() {
                 completed = completed && self.voices[i].1;
-                match &self.voices[i].0.waiting {
+                let (voice, finished) = &mut self.voices[i];
+                match &voice.waiting {
                     None => {
-                        if self.voices[i].1 {
+                        if *finished {
                             continue;
                         }
-                        if late_one > self.time_elapsed[i] {
-                            late_one = self.time_elapsed[i];
-                            late_one_index = i;
+                        if late_one.is_none_or(|(_, position)| position > voice.position) {
+                            late_one = Some((i, voice.position));
                         }
                     }
                     Some(s) => {
-                        if *s == self.point_flag.0 {
-                            self.voices[i].0.waiting = None;
-                            self.voice_audios[i] = self.voice_audios[i]
-                                .clone()
-                                .append(
-                                    AudioWave::new(
-                                        &Function::Const(0.0),
-                                        &Function::Const(0.0),
-                                        &(self.point_flag.1),
-                                        None,
-                                        None,
-                                        None,
-                                        None,
-                                    )
-                                    .expect("Sould be able to create rest"),
-                                    Some(1.0),
-                                )
-                                .expect(
-                                    "Waves generated by this module should always be compatible",
-                                );
-                            self.time_elapsed[i] += self.point_flag.1;
+                        if let Some(&at) = self.sync_points.get(s) {
+                            voice.waiting = None;
+                            voice.position = voice.position.max(at);
+                            released = true;
                         }
                     }
                 }
             }
-            let mut t: Float = 0.0;
-            match &self.voices[late_one_index].0.get_audio() {
-                Err(e) => {
-                    if e == "Empty" {
-                        self.voices[late_one_index].1 = true;
-                        continue;
-                    }
-                    return Err(e.clone());
-                }
-                Ok(v) => match v {
-                    Some(u) => {
-                        match u.0.clone() {
-                            Some(w) => {
-                                self.voice_audios[late_one_index] = self.voice_audios[late_one_index].clone().append(w.clone(), Some(1.0)).expect("Waves generated by this module should always be compatible");
-                                t = w.get_duration();
-                            }
-                            None => (),
-                        };
-                        match u.1.clone() {
-                            Some(s) => self.point_flag = (s, t),
-                            None => (),
-                        };
-                    }
-                    None => continue,
-                },
-            };
             if completed {
                 break;
             }
+            let Some((late_one_index, _)) = late_one else {
+                if released {
+                    continue;
+                }
+                return Err("Error: every remaining voice is waiting for a sync point".to_owned());
+            };
+            let (voice, finished) = &mut self.voices[late_one_index];
+            match voice.get_audio()? {
+                Step::Played(events) => {
+                    for event in events {
+                        self.timelines[late_one_index].push(event);
+                    }
+                }
+                Step::Synced(s) => {
+                    self.sync_points.entry(s).or_insert(voice.position);
+                    self.timelines[late_one_index].split();
+                }
+                Step::Waiting => self.timelines[late_one_index].split(),
+                Step::Finished => {
+                    *finished = true;
+                    self.timelines[late_one_index].extend_to(voice.position);
+                }
+            }
         }
 
         let mut result: AudioWave = AudioWave::new(
@@ -670,15 +988,285 @@ impl Manager {
             None,
             None,
             None,
-            None,
         )
         .expect("Should be able to create empty wave");
-        for audio in self.voice_audios.clone() {
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        for (i, (voice, _)) in self.voices.iter().enumerate() {
+            if let Some(name) = &voice.name {
+                if names.insert(name, i).is_some() {
+                    return Err(format!("Error: two voices are named '{}'", name));
+                }
+            }
+        }
+        let effects = self
+            .voices
+            .iter()
+            .flat_map(|(voice, _)| &voice.effects)
+            .chain(&master.effects);
+        let mut sources: HashMap<String, usize> = HashMap::new();
+        for name in effects.filter_map(|e| e.sidechain.as_ref()) {
+            match names.get(name.as_str()) {
+                Some(&i) => sources.insert(name.clone(), i),
+                None => return Err(format!("Error: no voice named '{}' to sidechain", name)),
+            };
+        }
+
+        let waves = timeline::mix_parallel(&self.timelines, None, self.threads)
+            .expect("Waves generated by this module should always be compatible");
+        // sidechains hear voices before their own effects
+        let sidechains: HashMap<String, AudioWave> = sources
+            .into_iter()
+            .map(|(name, i)| (name, waves[i].clone()))
+            .collect();
+        for (mut audio, (voice, _)) in waves.into_iter().zip(&self.voices) {
+            for effect in &voice.effects {
+                effect.apply(&mut audio, &sidechains);
+            }
+            audio.apply_gain(voice.gain);
             result = result
-                .clone()
                 .add(audio)
                 .expect("Waves generated by this module should always be compatible");
         }
-        return Ok(result);
+        master.process(&mut result, &sidechains);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn parallel_rendering_matches_sequential() {
+        let score = "humanize time 20ms seed 3; time 4/4; section a; C q; E | G q; D h; bar; end; \
+                     jump a 8; % octave 3; section b; C h; sync x; G h; end; jump b 6; % \
+                     wait x; glissando C E w; trill C D 8 w; A q"
+            .to_owned();
+        let mut sequential = Manager::new();
+        sequential.set_threads(1);
+        let mut parallel = Manager::new();
+        parallel.set_threads(4);
+        let expected = sequential.run(score.clone()).unwrap();
+        let actual = parallel.run(score).unwrap();
+        assert_eq!(expected.wave, actual.wave);
+    }
+
+    #[test]
+    fn time_never_runs_backwards() {
+        for score in [
+            "C; bpm -120; D",
+            "C; bpm 0; D",
+            "let x = 90; bpm x - x; C",
+            "C -1",
+            "duration -q; C",
+        ] {
+            let error = Manager::new().run(score.to_owned()).err();
+            assert!(
+                error.is_some_and(|e| e.starts_with("Invalid syntax")),
+                "{}",
+                score
+            );
+        }
+    }
+
+    #[test]
+    fn incomplete_glissandos_and_trills_are_rejected() {
+        for score in [
+            "glissando C",
+            "glissando",
+            "trill C",
+            "trill C D",
+            "trill C D 0",
+        ] {
+            let error = Manager::new().run(score.to_owned()).err();
+            assert!(
+                error.is_some_and(|e| e.starts_with("Invalid syntax")),
+                "{}",
+                score
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_directives_are_rejected() {
+        for score in ["filtr lowpass 1200; C", "C; compresor threshold -20", "c q"] {
+            let error = Manager::new().run(score.to_owned()).err();
+            assert!(
+                error.is_some_and(|e| e.contains("Unknown directive")),
+                "{}",
+                score
+            );
+        }
+    }
+
+    #[test]
+    fn voices_can_wait_for_any_bar() {
+        let first = "time 1/4; C q; bar; D q; bar";
+        let cases = [("wait bar 1; E q", "E q"), ("wait bar 2; E q", "_ q; E q")];
+        for (waiting, written) in cases {
+            let expected = Manager::new().run(format!("{} % {}", first, written));
+            let actual = Manager::new().run(format!("{} % {}", first, waiting));
+            assert_eq!(expected.unwrap().wave, actual.unwrap().wave, "{}", waiting);
+        }
+    }
+
+    #[test]
+    fn sidechained_voices_duck_their_target() {
+        // the kick is too quiet to be heard, but the compressor still hears it
+        let kick = "voice kick; gain -200; C q; _ h";
+        let pad = "A h.";
+        let plain = Manager::new().run(format!("{} % {}", kick, pad)).unwrap();
+        let ducked = Manager::new()
+            .run(format!(
+                "{} % compressor -40 20 1ms 10ms sidechain kick; {}",
+                kick, pad
+            ))
+            .unwrap();
+        let rms = |wave: &AudioWave, from: Float, to: Float| {
+            let part = &wave.wave[(from * SAMPLERATE as Float) as usize..]
+                [..((to - from) * SAMPLERATE as Float) as usize];
+            (part.iter().map(|x| x * x).sum::<Float>() / part.len() as Float).sqrt()
+        };
+        // during the kick, then once the compressor has let go
+        assert!(rms(&ducked, 0.1, 0.4) < 0.1 * rms(&plain, 0.1, 0.4));
+        let (after, before) = (rms(&ducked, 1.0, 1.3), rms(&plain, 1.0, 1.3));
+        assert!((after - before).abs() < 0.01 * before);
+    }
+
+    #[test]
+    fn the_master_eq_shapes_the_mix() {
+        let rms = |wave: &AudioWave| {
+            let part = &wave.wave[SAMPLERATE as usize / 2..SAMPLERATE as usize * 3 / 2];
+            (part.iter().map(|x| x * x).sum::<Float>() / part.len() as Float).sqrt()
+        };
+        let plain = Manager::new().run("A4 w".to_owned()).unwrap();
+        for (bands, gain) in [
+            ("master eq peak 440 2 12", 12.0),
+            (
+                "master eq peak 440 2 12; master eq lowshelf 2000 0.707 -6",
+                6.0,
+            ),
+            ("master eq lowpass 110", -24.0),
+        ] {
+            let equalized = Manager::new().run(format!("{}; A4 w", bands)).unwrap();
+            let actual = audiowave::gain_to_db(rms(&equalized) / rms(&plain));
+            assert!((actual - gain).abs() < 0.5, "{}: {} dB", bands, actual);
+        }
+    }
+
+    #[test]
+    fn transformations_can_use_variables() {
+        let section = "section a; C e; E q; end";
+        let literal = format!("{}; jump a transpose 2 tempo 1.5x", section);
+        let variables = format!("let k = 1; {}; jump a transpose k*2 tempo k+0.5", section);
+        let expected = Manager::new().run(literal).unwrap();
+        let actual = Manager::new().run(variables).unwrap();
+        assert_eq!(expected.wave, actual.wave);
+    }
+
+    #[test]
+    fn retrograde_keeps_bars_whole() {
+        let reversed = "time 4/4; section a; C w; bar; D q; E q; F h; bar; end; jump a retrograde";
+        let written = "time 4/4; F h; E q; D q; bar; C w; bar";
+        let expected = Manager::new().run(written.to_owned()).unwrap();
+        let actual = Manager::new().run(reversed.to_owned()).unwrap();
+        assert_eq!(expected.wave, actual.wave);
+    }
+
+    #[test]
+    fn retrograde_plays_each_note_with_its_state() {
+        let reversed = "section a; bpm 90; octave 5; C4 e; octave 3; D4 q; bpm 150; \
+                        tuplet 3:2 e { E4 F4 G4 }; end; jump a retrograde; A4"
+            .to_owned();
+        let written =
+            "bpm 150; octave 3; tuplet 3:2 e { G4 F4 E4 }; bpm 90; D4 q; octave 5; C4 e; \
+                       bpm 150; octave 3; A4"
+                .to_owned();
+        let expected = Manager::new().run(written).unwrap();
+        let actual = Manager::new().run(reversed).unwrap();
+        assert_eq!(expected.wave, actual.wave);
+    }
+
+    #[test]
+    fn note_durations_can_be_expressions_with_spaces() {
+        let expected = Manager::new().run("C 0.75; E | G 1.5".to_owned()).unwrap();
+        let actual = Manager::new()
+            .run("let beat = 0.5; C beat * 3/2; E | G ( beat + 1 ) ~ beat * 0".to_owned())
+            .unwrap();
+        assert_eq!(expected.wave, actual.wave);
+    }
+
+    #[test]
+    fn the_limiter_is_opt_in() {
+        let score = "master gain 12; C | E | G w";
+        let unlimited = Manager::new().run(score.to_owned()).unwrap();
+        let limited = Manager::new()
+            .run(format!("master limiter ceiling -1; {}", score))
+            .unwrap();
+        assert!(unlimited.peak() > 1.0);
+        assert!(limited.peak() <= audiowave::db_to_gain(-1.0) + 1e-6);
+    }
+
+    /// Sample at which the `n`th eighth note at 130 bpm starts, which is rarely a whole sample.
+    fn eighth_at_130(n: usize) -> usize {
+        (n as f64 * 0.5 * 60.0 / 130.0 * SAMPLERATE as f64).round() as usize
+    }
+
+    #[test]
+    fn long_sequences_land_on_exact_samples() {
+        let mut lines = vec!["bpm 130".to_owned()];
+        lines.extend((0..1000).map(|_| "C e".to_owned()));
+        lines.push("A e".to_owned());
+        let mut voice = Voice::new();
+        voice.contents = VoiceContent::Raw(lines);
+
+        let mut starts = Vec::new();
+        loop {
+            match voice.get_audio().unwrap() {
+                Step::Played(events) => starts.extend(events.iter().map(|e| e.start)),
+                Step::Finished => break,
+                _ => {}
+            }
+        }
+        let expected: Vec<usize> = (0..=1000).map(eighth_at_130).collect();
+        assert_eq!(starts, expected);
+        assert_eq!(voice.position, eighth_at_130(1001));
+    }
+
+    #[test]
+    fn rendered_notes_start_on_exact_samples() {
+        let rests = vec!["_ e"; 1000].join("; ");
+        let score = format!("bpm 130; {}; A e", rests);
+        let wave = Manager::new().run(score).unwrap();
+        let first_sound = wave.wave.iter().position(|x| *x != 0.0);
+        assert_eq!(first_sound, Some(eighth_at_130(1000)));
+        assert_eq!(wave.wave.len(), eighth_at_130(1001));
+    }
+
+    /// Renders a 10-minute score with 8 voices. Run it with
+    /// `cargo test --release -- --ignored --nocapture render_long_score`.
+    #[test]
+    #[ignore]
+    fn render_long_score() {
+        let voice = |octave: u8| {
+            format!(
+                "bpm 120; octave {}; section a; C e; D e; E e; F e; G e; A e; B e; C+ | E+ e; end; jump a 300;",
+                octave
+            )
+        };
+        let score = (1..=8).map(voice).collect::<Vec<String>>().join("%");
+
+        let start = Instant::now();
+        let wave = Manager::new().run(score).unwrap();
+        let elapsed = start.elapsed();
+        println!(
+            "rendered {} s of audio in {:?}",
+            wave.get_duration(),
+            elapsed
+        );
+
+        assert!((wave.get_duration() - 600.0).abs() < 1e-2);
+        assert!(elapsed.as_secs() < 30);
     }
 }
@@ -0,0 +1,266 @@
+use super::expr;
+use crate::definitions::Float;
+use std::collections::HashMap;
+
+/// Returns the length in beats of a note value symbol, where a quarter note is one beat.
+fn note_value(symbol: char) -> Option<Float> {
+    match symbol {
+        'w' => Some(4.0),
+        'h' => Some(2.0),
+        'q' => Some(1.0),
+        'e' => Some(0.5),
+        's' => Some(0.25),
+        't' => Some(0.125),
+        _ => None,
+    }
+}
+
+/// Parses a symbolic note value with optional dots, like `h.` or `q..`.
+pub fn parse_symbol(text: &str) -> Option<Float> {
+    let mut chars = text.chars();
+    let mut value = note_value(chars.next()?)?;
+    let mut added = value;
+    for c in chars {
+        if c != '.' {
+            return None;
+        }
+        added /= 2.0;
+        value += added;
+    }
+    Some(value)
+}
+
+/// Parses a duration in beats. It can be a note value (`q`, `e`, `h.`), a number or an
+/// arithmetic expression, and several of those can be tied with `~`, as in `h~e` or `q~beat`.
+/// Note values take precedence over variables with the same name.
+///
+/// Ties only join the durations of a single line: a note can't be tied to the next one.
+/// Durations can't be negative.
+pub fn parse_duration(text: &str, variables: &HashMap<String, Float>) -> Result<Float, String> {
+    let mut beats = 0.0;
+    let parts: Vec<&str> = text.split('~').map(str::trim).collect();
+    if parts.len() > 1 && parts.iter().any(|p| p.is_empty()) {
+        return Err(format!(
+            "'{}' is not a valid tie: ties join durations of the same line, like 'h~e', \
+             and notes can't be tied to the next line",
+            text
+        ));
+    }
+    for part in parts {
+        beats += match parse_symbol(part) {
+            Some(v) => v,
+            None => expr::evaluate(part, variables)?,
+        };
+    }
+    if !(beats >= 0.0 && beats.is_finite()) {
+        return Err(format!("'{}' is not a valid duration", text));
+    }
+    Ok(beats)
+}
+
+/// Expands the arguments of a `tuplet` line, such as `3:2 { C D E }` or `3:2 e { C q D e }`,
+/// into the notes (or chords) it contains and their durations in beats.
+/// A value before the braces is the default for notes that don't have one.
+pub fn parse_tuplet(
+    words: &[String],
+    default_duration: Float,
+    variables: &HashMap<String, Float>,
+) -> Result<Vec<(String, Float)>, String> {
+    let text = words.join(" ").replace('{', " { ").replace('}', " } ");
+    let words: Vec<&str> = text.split_whitespace().collect();
+
+    let ratio = words.first().ok_or("Missing tuplet ratio")?;
+    let (count, span) = ratio
+        .split_once(':')
+        .ok_or(format!("'{}' is not a valid tuplet ratio", ratio))?;
+    let count = expr::evaluate(count, variables)?;
+    let span = expr::evaluate(span, variables)?;
+    if count <= 0.0 || span <= 0.0 {
+        return Err(format!("'{}' is not a valid tuplet ratio", ratio));
+    }
+
+    let open = words
+        .iter()
+        .position(|w| *w == "{")
+        .ok_or("Missing '{' in tuplet")?;
+    let close = words
+        .iter()
+        .rposition(|w| *w == "}")
+        .ok_or("Missing '}' in tuplet")?;
+    if close < open {
+        return Err("Missing '}' in tuplet".to_owned());
+    }
+    let default_duration = match open {
+        1 => default_duration,
+        2 => parse_duration(words[1], variables)?,
+        _ => return Err("Unexpected words before '{' in tuplet".to_owned()),
+    };
+    if close != words.len() - 1 {
+        return Err("Unexpected words after '}' in tuplet".to_owned());
+    }
+
+    // each item is a list of notes played together and an optional duration
+    let mut items: Vec<(Vec<String>, Option<Float>)> = Vec::new();
+    let mut joining = false;
+    for word in &words[open + 1..close] {
+        if *word == "{" || *word == "}" || *word == "tuplet" {
+            return Err("Tuplets cannot be nested".to_owned());
+        }
+        if *word == "|" {
+            if items.is_empty() || joining {
+                return Err("Misplaced '|' in tuplet".to_owned());
+            }
+            joining = true;
+        } else if super::is_pitch(&word.to_string()) {
+            match items.last_mut() {
+                Some(item) if joining && item.1.is_none() => item.0.push(word.to_string()),
+                _ if joining => return Err("Misplaced '|' in tuplet".to_owned()),
+                _ => items.push((vec![word.to_string()], None)),
+            }
+            joining = false;
+        } else {
+            match items.last_mut() {
+                Some(item) if !joining && item.1.is_none() => {
+                    item.1 = Some(parse_duration(word, variables)?)
+                }
+                _ => return Err(format!("Unexpected '{}' in tuplet", word)),
+            }
+        }
+    }
+    if joining {
+        return Err("Misplaced '|' in tuplet".to_owned());
+    }
+    if items.is_empty() {
+        return Err("Empty tuplet".to_owned());
+    }
+
+    Ok(items
+        .into_iter()
+        .map(|(notes, beats)| {
+            (
+                notes.join(" | "),
+                beats.unwrap_or(default_duration) * span / count,
+            )
+        })
+        .collect())
+}
+
+/// Parses a time signature like `7/8` into its numerator and denominator.
+pub fn parse_time_signature(text: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("'{}' is not a valid time signature", text);
+    let (numerator, denominator) = text.split_once('/').ok_or_else(invalid)?;
+    let numerator: u32 = numerator.trim().parse().map_err(|_| invalid())?;
+    let denominator: u32 = denominator.trim().parse().map_err(|_| invalid())?;
+    if numerator == 0 || denominator == 0 {
+        return Err(invalid());
+    }
+    Ok((numerator, denominator))
+}
+
+/// Returns the length in beats (quarter notes) of a bar in the given time signature.
+pub fn bar_length((numerator, denominator): (u32, u32)) -> Float {
+    numerator as Float * 4.0 / denominator as Float
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duration(text: &str) -> Result<Float, String> {
+        let variables = HashMap::from([("beat".to_owned(), 0.5)]);
+        parse_duration(text, &variables)
+    }
+
+    fn tuplet(text: &str) -> Result<Vec<(String, Float)>, String> {
+        let words: Vec<String> = text.split_whitespace().map(String::from).collect();
+        parse_tuplet(&words, 1.0, &HashMap::new())
+    }
+
+    #[test]
+    fn note_values_are_counted_in_quarter_notes() {
+        let values: Vec<Option<Float>> = "whqestx".chars().map(note_value).collect();
+        assert_eq!(
+            values,
+            [
+                Some(4.0),
+                Some(2.0),
+                Some(1.0),
+                Some(0.5),
+                Some(0.25),
+                Some(0.125),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn dots_add_half_of_the_previous_value() {
+        assert_eq!(duration("h."), Ok(3.0));
+        assert_eq!(duration("q.."), Ok(1.75));
+        assert_eq!(duration("e..."), Ok(0.9375));
+        assert_eq!(parse_symbol("q.x"), None);
+    }
+
+    #[test]
+    fn ties_add_up_the_durations_of_a_line() {
+        assert_eq!(duration("h~e"), Ok(2.5));
+        assert_eq!(duration("q ~ beat * 3"), Ok(2.5));
+        assert_eq!(duration("1.5"), Ok(1.5));
+        assert!(duration("-1").is_err());
+        assert!(duration("q~-beat*4").is_err());
+        for text in ["h~", "~e", "q~~e"] {
+            let error = duration(text).unwrap_err();
+            assert!(
+                error.contains("can't be tied to the next line"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn tuplets_share_their_span_between_their_notes() {
+        let notes = tuplet("3:2 e {C D | F E}").unwrap();
+        let third = 0.5 * 2.0 / 3.0;
+        assert_eq!(
+            notes,
+            [
+                ("C".to_owned(), third),
+                ("D | F".to_owned(), third),
+                ("E".to_owned(), third)
+            ]
+        );
+        let notes = tuplet("5:4 { C q D e E e F q G q }").unwrap();
+        let beats: Vec<Float> = notes.iter().map(|(_, b)| b * 5.0).collect();
+        assert_eq!(beats, [4.0, 2.0, 2.0, 4.0, 4.0]);
+    }
+
+    #[test]
+    fn invalid_tuplets_are_rejected() {
+        assert!(tuplet("3 { C D E }").is_err());
+        assert!(tuplet("0:2 { C D E }").is_err());
+        assert!(tuplet("3:2 { }").is_err());
+        assert!(tuplet("3:2 { C | }").is_err());
+        assert!(tuplet("3:2 { C D E } F").is_err());
+        assert_eq!(
+            tuplet("3:2 { C { D E } F }"),
+            Err("Tuplets cannot be nested".to_owned())
+        );
+        assert_eq!(
+            tuplet("3:2 { C tuplet 3:2 { D E F } }"),
+            Err("Tuplets cannot be nested".to_owned())
+        );
+    }
+
+    #[test]
+    fn bars_last_as_many_beats_as_their_signature() {
+        assert_eq!(bar_length((4, 4)), 4.0);
+        assert_eq!(bar_length((3, 4)), 3.0);
+        assert_eq!(bar_length((6, 8)), 3.0);
+        assert_eq!(bar_length((7, 8)), 3.5);
+        assert_eq!(bar_length((2, 2)), 4.0);
+        assert_eq!(parse_time_signature("7 / 8"), Ok((7, 8)));
+        assert!(parse_time_signature("0/4").is_err());
+        assert!(parse_time_signature("4").is_err());
+    }
+}
@@ -0,0 +1,493 @@
+use super::transform;
+use crate::definitions::Float;
+use std::collections::HashMap;
+
+/// A named list of lines that can be played with `jump`, optionally taking arguments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Section {
+    pub params: Vec<String>,
+    pub lines: Vec<String>,
+}
+
+/// Splits `name(a, b) rest` into the name, the arguments between parentheses and the
+/// whitespace separated words after them. Without parentheses there are no arguments.
+pub fn parse_call(text: &str) -> Result<(String, Vec<String>, Vec<String>), String> {
+    let text = text.trim();
+    let name_end = text
+        .find(|c: char| c == '(' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let name = text[..name_end].to_owned();
+    if name.is_empty() {
+        return Err("Missing section name".to_owned());
+    }
+    let rest = text[name_end..].trim_start();
+
+    if !rest.starts_with('(') {
+        let words = rest.split_whitespace().map(|w| w.to_owned()).collect();
+        return Ok((name, Vec::new(), words));
+    }
+    let close = rest
+        .find(')')
+        .ok_or(format!("Missing ')' after the arguments of '{}'", name))?;
+    let args: Vec<String> = rest[1..close]
+        .split(',')
+        .map(|a| a.trim().to_owned())
+        .collect();
+    let args = if args.len() == 1 && args[0].is_empty() {
+        Vec::new()
+    } else {
+        args
+    };
+    if args.iter().any(|a| a.is_empty()) {
+        return Err(format!("Empty argument in the call of '{}'", name));
+    }
+    let words = rest[close + 1..]
+        .split_whitespace()
+        .map(|w| w.to_owned())
+        .collect();
+    Ok((name, args, words))
+}
+
+/// Words starting a directive, which can't be parameter names since every occurrence of a
+/// parameter in the section is replaced.
+const KEYWORDS: [&str; 33] = [
+    "let",
+    "bpm",
+    "duration",
+    "tuning",
+    "octave",
+    "intensity",
+    "transform",
+    "state",
+    "waveform",
+    "harmonics",
+    "sampler",
+    "synth",
+    "time",
+    "swing",
+    "groove",
+    "humanize",
+    "gain",
+    "voice",
+    "compressor",
+    "gate",
+    "filter",
+    "bar",
+    "wait",
+    "sync",
+    "glissando",
+    "trill",
+    "tuplet",
+    "section",
+    "global",
+    "end",
+    "jump",
+    "include",
+    "master",
+];
+
+/// Checks that the parameters of a section header are usable as placeholders.
+/// They must start with a lowercase letter so they can't be confused with notes, and can't
+/// be a keyword or a note value like `q`.
+pub fn validate_params(params: &[String]) -> Result<(), String> {
+    for (i, p) in params.iter().enumerate() {
+        let valid = p.starts_with(|c: char| c.is_ascii_lowercase())
+            && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("'{}' is not a valid parameter name", p));
+        }
+        if KEYWORDS.contains(&p.as_str()) || super::rhythm::parse_symbol(p).is_some() {
+            return Err(format!("'{}' is reserved and can't be a parameter name", p));
+        }
+        if params[..i].contains(p) {
+            return Err(format!("Parameter '{}' is declared twice", p));
+        }
+    }
+    Ok(())
+}
+
+/// Shifts a note name or frequency literal by some semitones, keeping it as text.
+/// Notes get the shift as microtones, so `C#+` shifted by 7 is `C#(700c)+`.
+pub fn shift_note_text(note: &str, semitones: Float) -> Option<String> {
+    if note == "_" {
+        return Some(note.to_owned());
+    }
+    if let Some(hz) = note.strip_suffix("Hz") {
+        let hz: Float = hz.parse().ok()?;
+        return Some(format!("{}Hz", hz * (2.0 as Float).powf(semitones / 12.0)));
+    }
+    super::note_to_semitone(&note.to_owned(), &Some(4))?;
+
+    let cents = format!("{}c", semitones * 100.0);
+    let accidentals_end = 1 + note[1..]
+        .find(|c: char| c != '#' && c != 'b')
+        .unwrap_or(note.len() - 1);
+    let shifted = if note[accidentals_end..].starts_with('(') {
+        let close = accidentals_end + note[accidentals_end..].find(')')?;
+        format!("{}{}{}", &note[..close], cents, &note[close..])
+    } else {
+        format!(
+            "{}({}){}",
+            &note[..accidentals_end],
+            cents,
+            &note[accidentals_end..]
+        )
+    };
+    Some(shifted)
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Reads the shift written right after a parameter: `+7` or `-2.5` are semitones and
+/// octave marks like `+` or `--` are octaves. Returns the shift and its length in bytes.
+fn read_shift(text: &str) -> Option<(Float, usize)> {
+    if !text.starts_with(['+', '-']) {
+        return None;
+    }
+    let number_len = 1 + text[1..]
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len() - 1);
+    if number_len > 1 {
+        return Some((text[..number_len].parse().ok()?, number_len));
+    }
+    let marks_len = text.find(|c| c != '+' && c != '-').unwrap_or(text.len());
+    let octaves: Float = text[..marks_len]
+        .chars()
+        .map(|c| if c == '+' { 1.0 } else { -1.0 })
+        .sum();
+    Some((12.0 * octaves, marks_len))
+}
+
+/// Replaces the parameters found in a line by their arguments. Besides the plain name,
+/// `root+7` or `root-2.5` shift a pitch argument by semitones and octave marks like
+/// `root+` or `root--` shift it by octaves. Other arguments are replaced as they are, so
+/// `len+1` becomes an expression.
+fn substitute_line(line: &str, params: &[String], args: &[String]) -> Result<String, String> {
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+    let mut previous: Option<char> = None;
+    while let Some(c) = rest.chars().next() {
+        let starts_identifier = c.is_ascii_lowercase() && !previous.is_some_and(is_identifier_char);
+        if !starts_identifier {
+            result.push(c);
+            previous = Some(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let len = rest.find(|c| !is_identifier_char(c)).unwrap_or(rest.len());
+        let identifier = &rest[..len];
+        rest = &rest[len..];
+        previous = identifier.chars().last();
+        let arg = match params.iter().position(|p| p == identifier) {
+            Some(i) => &args[i],
+            None => {
+                result.push_str(identifier);
+                continue;
+            }
+        };
+        match read_shift(rest) {
+            Some((semitones, shift_len)) if super::is_pitch(arg) => {
+                let shifted = shift_note_text(arg, semitones)
+                    .ok_or(format!("Cannot shift '{}' since it is not a pitch", arg))?;
+                result.push_str(&shifted);
+                rest = &rest[shift_len..];
+            }
+            _ => result.push_str(arg),
+        }
+    }
+    Ok(result)
+}
+
+impl Section {
+    /// Returns the lines of the section with its parameters replaced by `args`.
+    pub fn instantiate(&self, name: &str, args: &[String]) -> Result<Vec<String>, String> {
+        if args.len() != self.params.len() {
+            return Err(format!(
+                "Section '{}' takes {} argument(s) but {} were given",
+                name,
+                self.params.len(),
+                args.len()
+            ));
+        }
+        if self.params.is_empty() {
+            return Ok(self.lines.clone());
+        }
+        let mut result = Vec::with_capacity(self.lines.len());
+        for line in &self.lines {
+            result.push(substitute_line(line, &self.params, args)?);
+        }
+        Ok(result)
+    }
+}
+
+/// The sections a voice can jump to: its own and the global ones, which every voice sees
+/// regardless of where they were defined. Voice sections shadow global ones.
+pub struct Scope<'a> {
+    pub global: &'a HashMap<String, Section>,
+    pub local: &'a HashMap<String, Section>,
+    /// The sections of every voice, to point out jumps to a section of another voice.
+    pub voices: &'a [HashMap<String, Section>],
+}
+
+impl Scope<'_> {
+    pub fn get(&self, name: &str) -> Option<&Section> {
+        self.local.get(name).or_else(|| self.global.get(name))
+    }
+
+    /// Returns the number, from 1, of the first voice with its own section named `name`.
+    fn voice_defining(&self, name: &str) -> Option<usize> {
+        self.voices
+            .iter()
+            .position(|v| v.contains_key(name))
+            .map(|i| i + 1)
+    }
+}
+
+/// Replaces every `jump` in `lines` by the lines of the section it refers to, recursively.
+/// `chain` holds the sections currently being expanded, to report jumps that never end.
+pub fn expand(
+    lines: &[String],
+    scope: &Scope,
+    chain: &mut Vec<String>,
+) -> Result<Vec<String>, String> {
+    let mut result = Vec::with_capacity(lines.len());
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first() == Some(&"jump") && parse_bar_target(&words).is_none() {
+            result.extend(expand_jump(line, scope, chain)?);
+        } else {
+            result.push(line.clone());
+        }
+    }
+    Ok(result)
+}
+
+fn expand_jump(line: &str, scope: &Scope, chain: &mut Vec<String>) -> Result<Vec<String>, String> {
+    let (section_name, args, aux) = match parse_call(&line[4..]) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Invalid syntax on line: {}\n{}", line, e)),
+    };
+    let section = match scope.get(&section_name) {
+        Some(s) => s,
+        None => {
+            return Err(match scope.voice_defining(&section_name) {
+                Some(voice) => format!(
+                    "Error on line: {}\nNo section named '{}' in this voice. Voice {} defines \
+                     one, but sections belong to the voice defining them unless defined with \
+                     'global section {}'",
+                    line, section_name, voice, section_name
+                ),
+                None => format!(
+                    "Error on line: {}\nNo section named '{}'",
+                    line, section_name
+                ),
+            })
+        }
+    };
+    if chain.contains(&section_name) {
+        return Err(format!(
+            "Error on line: {}\nSection '{}' jumps to itself: {} -> {}",
+            line,
+            section_name,
+            chain.join(" -> "),
+            section_name
+        ));
+    }
+    let body = match section.instantiate(&section_name, &args) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Error on line: {}\n{}", line, e)),
+    };
+    chain.push(section_name);
+    let body = expand(&body, scope, chain);
+    chain.pop();
+    apply_modifiers(body?, &aux, line)
+}
+
+/// Repetitions and transformations written after the target of a jump.
+struct Modifiers {
+    repetitions: u32,
+    retrograde: bool,
+    /// The `transform push` line applying the other transformations, if there are any.
+    directive: Option<String>,
+}
+
+impl Modifiers {
+    fn parse(aux: &[String], line: &str) -> Result<Modifiers, String> {
+        let mut repetitions: u32 = 1;
+        let mut first_transformation = 0;
+        if let Some(Ok(u)) = aux.first().map(|w| w.parse::<u32>()) {
+            repetitions = u;
+            first_transformation = 1;
+        }
+        let transformations = match transform::split_transformations(&aux[first_transformation..]) {
+            Ok(t) => t,
+            Err(e) => return Err(format!("Invalid syntax on line: {}\n{}", line, e)),
+        };
+        let others: Vec<String> = transformations
+            .iter()
+            .filter(|t| t[0] != "retrograde")
+            .flat_map(|t| t.iter().cloned())
+            .collect();
+        Ok(Modifiers {
+            repetitions,
+            retrograde: transformations.iter().any(|t| t[0] == "retrograde"),
+            directive: (!others.is_empty()).then(|| format!("transform push {}", others.join(" "))),
+        })
+    }
+
+    /// Applies the transformations to `body`, without repeating it.
+    fn transform(&self, mut body: Vec<String>) -> Vec<String> {
+        if self.retrograde {
+            body = transform::retrograde(&body);
+        }
+        if let Some(directive) = &self.directive {
+            body.insert(0, directive.clone());
+            body.push("transform pop".to_owned());
+        }
+        body
+    }
+}
+
+/// Applies the repetitions and transformations written after the target of a jump.
+fn apply_modifiers(body: Vec<String>, aux: &[String], line: &str) -> Result<Vec<String>, String> {
+    let modifiers = Modifiers::parse(aux, line)?;
+    let once = modifiers.transform(body);
+    let mut result = Vec::with_capacity(once.len() * modifiers.repetitions as usize);
+    for _ in 0..modifiers.repetitions {
+        result.extend(once.iter().cloned());
+    }
+    Ok(result)
+}
+
+/// Parses the target of `jump bar 3` or `jump bar 3-4`, returning the first and last bar.
+fn parse_bar_target(words: &[&str]) -> Option<(usize, usize)> {
+    if words.len() < 3 || words[0] != "jump" || words[1] != "bar" {
+        return None;
+    }
+    let (first, last) = words[2].split_once('-').unwrap_or((words[2], words[2]));
+    Some((first.parse().ok()?, last.parse().ok()?))
+}
+
+/// Replaces every `jump bar N` (or `jump bar N-M`) by the lines of those bars, which are
+/// separated by `bar` lines and numbered from 1. Only bars that already ended can be played.
+///
+/// The bars are played again as new bars, each closed by its own `bar` line, so they are
+/// numbered like any other bar and the line after the jump starts a new bar. Transformations
+/// apply to each bar on its own, and `retrograde` also plays the bars in reverse order.
+pub fn expand_bar_jumps(lines: Vec<String>) -> Result<Vec<String>, String> {
+    let mut result: Vec<String> = Vec::with_capacity(lines.len());
+    // index in `result` where each bar starts
+    let mut bar_starts: Vec<usize> = vec![0];
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.first() == Some(&"bar") {
+            result.push(line);
+            bar_starts.push(result.len());
+            continue;
+        }
+        let (first, last) = match parse_bar_target(&words) {
+            Some(v) => v,
+            None => {
+                result.push(line);
+                continue;
+            }
+        };
+        let ended = bar_starts.len() - 1;
+        if first == 0 || first > last || last > ended {
+            return Err(format!(
+                "Error on line: {}\nCannot jump to bars {}-{}, only bars 1-{} have ended",
+                line, first, last, ended
+            ));
+        }
+        let aux: Vec<String> = words[3..].iter().map(|w| w.to_string()).collect();
+        let modifiers = Modifiers::parse(&aux, &line)?;
+        // the lines of each bar, without the `bar` line closing it
+        let mut bars: Vec<Vec<String>> = (first..=last)
+            .map(|bar| result[bar_starts[bar - 1]..bar_starts[bar] - 1].to_vec())
+            .collect();
+        if modifiers.retrograde {
+            bars.reverse();
+        }
+        for _ in 0..modifiers.repetitions {
+            for bar in &bars {
+                result.extend(modifiers.transform(bar.clone()));
+                result.push("bar".to_owned());
+                bar_starts.push(result.len());
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split(';').map(|l| l.trim().to_owned()).collect()
+    }
+
+    #[test]
+    fn jumped_bars_are_numbered_like_other_bars() {
+        let expanded = expand_bar_jumps(lines(
+            "C;bar;D;bar;E;bar;jump bar 1-2;jump bar 4-5;jump bar 6",
+        ))
+        .unwrap();
+        assert_eq!(
+            expanded,
+            lines("C;bar;D;bar;E;bar;C;bar;D;bar;C;bar;D;bar;C;bar")
+        );
+    }
+
+    #[test]
+    fn jumps_only_reach_bars_that_ended() {
+        let error = expand_bar_jumps(lines("C;bar;D;bar;jump bar 1-2;jump bar 5")).unwrap_err();
+        assert!(error.contains("only bars 1-4 have ended"), "{}", error);
+    }
+
+    #[test]
+    fn jumps_to_sections_of_other_voices_point_to_global_sections() {
+        let error = crate::parser::preprocess("C % section riff; D; end % jump riff".to_owned())
+            .unwrap_err();
+        assert!(error.contains("Voice 2 defines one"), "{}", error);
+        assert!(error.contains("'global section riff'"), "{}", error);
+        let error = crate::parser::preprocess("jump riff".to_owned()).unwrap_err();
+        assert!(error.ends_with("No section named 'riff'"), "{}", error);
+    }
+
+    #[test]
+    fn only_pitch_arguments_are_shifted() {
+        let voices = crate::parser::preprocess(
+            "section r(root, len); root len+1; root+7 len*2; root- len; end; jump r(C, 0.5)"
+                .to_owned(),
+        )
+        .unwrap();
+        assert_eq!(voices, [vec!["C 0.5+1", "C(700c) 0.5*2", "C(-1200c) 0.5"]]);
+    }
+
+    #[test]
+    fn reserved_parameter_names_are_rejected() {
+        for header in [
+            "section r(e)",
+            "section r(root, q)",
+            "section r(bar)",
+            "section r(let)",
+        ] {
+            let score = format!("{}; C e; end; jump r(D)", header);
+            let error = crate::parser::preprocess(score).unwrap_err();
+            assert!(error.contains("reserved"), "{}", error);
+        }
+        assert!(validate_params(&["root".to_owned(), "len".to_owned()]).is_ok());
+    }
+
+    #[test]
+    fn transformed_bar_jumps_keep_each_bar_whole() {
+        let expanded = expand_bar_jumps(lines("C;D;bar;E;bar;jump bar 1-2 2 retrograde")).unwrap();
+        assert_eq!(expanded, lines("C;D;bar;E;bar;E;bar;D;C;bar;E;bar;D;C;bar"));
+        let expanded = expand_bar_jumps(lines("C;bar;jump bar 1 transpose 2;jump bar 2")).unwrap();
+        assert_eq!(expanded[2], "transform push transpose 2");
+        assert_eq!(&expanded[2..6], &expanded[6..]);
+    }
+}
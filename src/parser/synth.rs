@@ -0,0 +1,585 @@
+use super::{expr, humanize, mix};
+use crate::audiowave::{self, AudioWave, SampleLoop};
+use crate::definitions::Float;
+use crate::function::Interpolation;
+use crate::instrument::{
+    Additive, Algorithm, Envelope, FilterModel, Flute, Fm, Instrument, ModalBar, Operator, Partial,
+    PluckedString, Subtractive, Waveform, Wavetable, Zone,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What a zone needs from a recording, as read from its file.
+struct Recording {
+    sample: Arc<Vec<Float>>,
+    samplerate: u32,
+    /// Unity note and first loop of the `smpl` chunk, if the file has one.
+    unity_note: Option<u32>,
+    sustain_loop: Option<SampleLoop>,
+}
+
+/// Recordings and wavetables read so far while rendering a score, by path, so a file named
+/// by several lines, or by a line played several times, is only read and band-limited once.
+#[derive(Default)]
+pub struct Files {
+    recordings: HashMap<PathBuf, Recording>,
+    tables: HashMap<(PathBuf, usize), Wavetable>,
+}
+
+impl Files {
+    fn recording(&mut self, path: &Path) -> Result<&Recording, audiowave::WavImportError> {
+        if !self.recordings.contains_key(path) {
+            let wave = AudioWave::from_wav(path)?;
+            let info = audiowave::read_sampler_info(path)?;
+            let recording = Recording {
+                samplerate: wave.get_samplerate(),
+                sample: Arc::new(wave.wave),
+                unity_note: info.as_ref().map(|i| i.unity_note),
+                sustain_loop: info.and_then(|i| i.loops.first().copied()),
+            };
+            self.recordings.insert(path.to_path_buf(), recording);
+        }
+        Ok(&self.recordings[path])
+    }
+
+    /// The table read from `path` with frames of `frame` samples. Copies share their frames.
+    fn table(&mut self, path: &Path, frame: usize) -> Result<Wavetable, audiowave::WavImportError> {
+        let key = (path.to_path_buf(), frame);
+        if !self.tables.contains_key(&key) {
+            let table = Wavetable::load(path, frame)?;
+            self.tables.insert(key.clone(), table);
+        }
+        Ok(self.tables[&key].clone())
+    }
+}
+
+/// Parses the arguments of a `waveform` line, which plays the following notes with a plain
+/// oscillator, like `waveform saw`, or with a wavetable read from a WAV file relative to
+/// `directory`: `waveform table <file> [frame <samples>] [position <start> [end]]
+/// [interpolation linear|cubic]`. Tables already in `files` aren't read again.
+///
+/// - `frame` is the length of each cycle in the file, 2048 samples by default.
+/// - `position` picks the cycle played, from 0 for the first to 1 for the last, blending the
+///   two closest. With an end, it moves from the start to the end over each note. 0 by
+///   default.
+/// - `interpolation` is cubic by default.
+pub fn parse_waveform(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+    directory: &Path,
+    files: &mut Files,
+) -> Result<Instrument, String> {
+    match words {
+        [name] if name != "table" => Waveform::from_name(name)
+            .map(Instrument::Oscillator)
+            .ok_or(format!("Unknown waveform '{}'", name)),
+        [table, file, settings @ ..] if table == "table" => {
+            let mut frame = 2048;
+            let mut position = (0.0, 0.0);
+            let mut interpolation = Interpolation::Cubic;
+            let mut i = 0;
+            while i < settings.len() {
+                let value = settings
+                    .get(i + 1)
+                    .ok_or(format!("Missing value for '{}'", settings[i]))?;
+                match settings[i].as_str() {
+                    "frame" => {
+                        let v = expr::evaluate(value, variables)?;
+                        if v.fract() != 0.0 || v < 1.0 {
+                            return Err(format!("'{}' is not a valid frame length", v));
+                        }
+                        frame = v as usize;
+                    }
+                    "position" => {
+                        let start = expr::evaluate(value, variables)?;
+                        let end = settings
+                            .get(i + 2)
+                            .and_then(|w| expr::evaluate(w, variables).ok());
+                        position = (start, end.unwrap_or(start));
+                        if end.is_some() {
+                            i += 1;
+                        }
+                    }
+                    "interpolation" => {
+                        interpolation = Interpolation::from_name(value)
+                            .ok_or(format!("Unknown interpolation '{}'", value))?
+                    }
+                    other => return Err(format!("Unknown wavetable setting '{}'", other)),
+                }
+                i += 2;
+            }
+            let path = directory.join(file.trim_matches('"'));
+            let mut table = files
+                .table(&path, frame)
+                .map_err(|e| format!("Error: cannot read '{}': {}", path.display(), e))?;
+            table.position = position;
+            table.interpolation = interpolation;
+            Ok(Instrument::Wavetable(table))
+        }
+        _ => Err(
+            "Expected 'waveform <sine|saw|square|triangle>' or 'waveform table <file>'".to_owned(),
+        ),
+    }
+}
+
+/// Parses the arguments of a `harmonics` line, which plays the following notes as a sum of
+/// partials: `harmonics <amplitudes> [phases <phases>] [stretch <amount>] [envelope ...]`.
+///
+/// - The amplitudes are those of the harmonics in order, relative to each other.
+/// - `phases` gives the phases of the first harmonics, in cycles. Others start at 0.
+/// - `stretch` is the inharmonicity coefficient, 0 by default.
+/// - `envelope <attack> <decay> <sustain> <release>` sets the envelope of every harmonic,
+///   and `envelope <n> <attack> <decay> <sustain> <release>` that of the n-th one only. They
+///   apply in order, and harmonics default to a short fade in and out.
+///
+/// Like `harmonics 1 0.5 0.33 0.25 envelope 4 0 1s 0 1s`.
+pub fn parse_harmonics(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Instrument, String> {
+    let is_setting = |w: &String| ["phases", "stretch", "envelope"].contains(&w.as_str());
+    let count = words.iter().position(is_setting).unwrap_or(words.len());
+    if count == 0 {
+        return Err("Expected 'harmonics <amplitudes>'".to_owned());
+    }
+    let mut partials = words[..count]
+        .iter()
+        .map(|w| {
+            Ok(Partial {
+                amplitude: expr::evaluate(w, variables)?,
+                phase: 0.0,
+                envelope: Envelope::default(),
+            })
+        })
+        .collect::<Result<Vec<Partial>, String>>()?;
+    let mut stretch = 0.0;
+    let mut i = count;
+    while i < words.len() {
+        let end = words[i + 1..]
+            .iter()
+            .position(is_setting)
+            .map_or(words.len(), |j| i + 1 + j);
+        let values = &words[i + 1..end];
+        match words[i].as_str() {
+            "phases" => {
+                if values.len() > partials.len() {
+                    return Err("More phases than harmonics".to_owned());
+                }
+                for (partial, value) in partials.iter_mut().zip(values) {
+                    partial.phase = expr::evaluate(value, variables)?;
+                }
+            }
+            "stretch" => match values {
+                [value] => stretch = expr::evaluate(value, variables)?.max(0.0),
+                _ => return Err("Expected 'stretch <amount>'".to_owned()),
+            },
+            _ => match values.len() {
+                4 => {
+                    let envelope = parse_envelope(values, variables)?;
+                    for partial in &mut partials {
+                        partial.envelope = envelope;
+                    }
+                }
+                5 => {
+                    let n = expr::evaluate(&values[0], variables)?;
+                    if n.fract() != 0.0 || n < 1.0 || n as usize > partials.len() {
+                        return Err(format!("There is no harmonic {}", n));
+                    }
+                    partials[n as usize - 1].envelope = parse_envelope(&values[1..], variables)?;
+                }
+                _ => {
+                    return Err(
+                        "Expected 'envelope [harmonic] <attack> <decay> <sustain> <release>'"
+                            .to_owned(),
+                    )
+                }
+            },
+        }
+        i = end;
+    }
+    Ok(Instrument::Additive(Additive { partials, stretch }))
+}
+
+/// Parses the arguments of a `synth` line: the kind of instrument followed by its settings.
+/// Omitted settings keep their default.
+///
+/// `synth subtractive [waveform]` takes a saw by default, and the settings:
+///
+/// - `cutoff <frequency>`: cutoff of the filter for a note at middle C, 800 Hz by default.
+/// - `resonance <0 to 1>`: 0.3 by default.
+/// - `filter svf|ladder`: the state-variable filter by default.
+/// - `envelope <attack> <decay> <sustain> <release>`: the filter envelope, 5 ms, 300 ms, 0
+///   and 100 ms by default.
+/// - `amount <frequency>`: how far the envelope moves the cutoff up, 2000 Hz by default.
+/// - `keytrack <amount>`: how much the cutoff follows the pitch, 0.5 by default.
+/// - `amp <attack> <decay> <sustain> <release>`: the amplitude envelope, 5 ms, 0, 1 and
+///   20 ms by default.
+///
+/// `synth fm [algorithm] op <ratio> <index> [<attack> <decay> <sustain> <release>] op ...`
+/// takes 2 to 6 operators, each with its frequency relative to the note, its modulation index
+/// or output level, and optionally its envelope. The algorithm is `stack` by default, or
+/// `branch`, `pairs` or `parallel`.
+///
+/// Physical models take settings as `<setting> <value>` pairs:
+///
+/// - `synth string [damping <0 to 1>] [pick <0 to 0.5>] [decay <time>] [release <time>]`, a
+///   plucked string, with 0.5 of damping, plucked at 0.15 and 3 s of decay by default.
+/// - `synth flute [breath <pressure>] [noise <amount>] [vibrato <depth>] [release <time>]`,
+///   blown with a pressure of 1, 0.15 of noise and 0.05 of vibrato by default.
+/// - `synth bar [hardness <0 to 1>] [position <0 to 1>] [decay <time>] [release <time>]`, a
+///   marimba bar struck at 0.3 with a mallet of hardness 0.5, with 500 ms of decay by
+///   default.
+///
+/// The release is how long the instrument keeps sounding once the note is let go: 200 ms
+/// for strings, 80 ms for flutes and 500 ms for bars by default.
+pub fn parse(words: &[String], variables: &HashMap<String, Float>) -> Result<Instrument, String> {
+    let kind = words.first().ok_or("Missing instrument")?;
+    match kind.as_str() {
+        "subtractive" => Ok(Instrument::Subtractive(parse_subtractive(
+            &words[1..],
+            variables,
+        )?)),
+        "fm" => Ok(Instrument::Fm(parse_fm(&words[1..], variables)?)),
+        "string" => {
+            let mut model = PluckedString::default();
+            parse_settings(&words[1..], |setting, value| {
+                match setting {
+                    "damping" => model.damping = parse_fraction(value, 1.0, variables)?,
+                    "pick" => model.pick = parse_fraction(value, 0.5, variables)?,
+                    "decay" => model.decay = humanize::parse_time(value, variables)?.abs(),
+                    "release" => model.release = humanize::parse_time(value, variables)?.abs(),
+                    other => return Err(format!("Unknown string setting '{}'", other)),
+                }
+                Ok(())
+            })?;
+            Ok(Instrument::String(model))
+        }
+        "flute" => {
+            let mut model = Flute::default();
+            parse_settings(&words[1..], |setting, value| {
+                match setting {
+                    "breath" => model.breath = expr::evaluate(value, variables)?.max(0.0),
+                    "noise" => model.noise = expr::evaluate(value, variables)?.max(0.0),
+                    "vibrato" => model.vibrato = expr::evaluate(value, variables)?.max(0.0),
+                    "release" => model.release = humanize::parse_time(value, variables)?.abs(),
+                    other => return Err(format!("Unknown flute setting '{}'", other)),
+                }
+                Ok(())
+            })?;
+            Ok(Instrument::Flute(model))
+        }
+        "bar" => {
+            let mut model = ModalBar::default();
+            parse_settings(&words[1..], |setting, value| {
+                match setting {
+                    "hardness" => model.hardness = parse_fraction(value, 1.0, variables)?,
+                    "position" => model.position = parse_fraction(value, 1.0, variables)?,
+                    "decay" => model.decay = humanize::parse_time(value, variables)?.abs(),
+                    "release" => model.release = humanize::parse_time(value, variables)?.abs(),
+                    other => return Err(format!("Unknown bar setting '{}'", other)),
+                }
+                Ok(())
+            })?;
+            Ok(Instrument::Bar(model))
+        }
+        other => Err(format!("Unknown instrument '{}'", other)),
+    }
+}
+
+/// Parses `<setting> <value>` pairs, applying each one in order.
+fn parse_settings(
+    words: &[String],
+    mut apply: impl FnMut(&str, &String) -> Result<(), String>,
+) -> Result<(), String> {
+    for pair in words.chunks(2) {
+        match pair {
+            [setting, value] => apply(setting, value)?,
+            _ => return Err(format!("Missing value for '{}'", pair[0])),
+        }
+    }
+    Ok(())
+}
+
+/// Parses a number between 0 and `max`.
+fn parse_fraction(
+    word: &str,
+    max: Float,
+    variables: &HashMap<String, Float>,
+) -> Result<Float, String> {
+    let value = expr::evaluate(word, variables)?;
+    if !(0.0..=max).contains(&value) {
+        return Err(format!("'{}' is not between 0 and {}", value, max));
+    }
+    Ok(value)
+}
+
+/// Parses the four values following an envelope setting.
+fn parse_envelope(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Envelope, String> {
+    let [attack, decay, sustain, release] = words else {
+        return Err("Expected '<attack> <decay> <sustain> <release>'".to_owned());
+    };
+    let sustain = expr::evaluate(sustain, variables)?;
+    if !(0.0..=1.0).contains(&sustain) {
+        return Err(format!("'{}' is not a valid sustain level", sustain));
+    }
+    Ok(Envelope {
+        attack: humanize::parse_time(attack, variables)?.abs(),
+        decay: humanize::parse_time(decay, variables)?.abs(),
+        sustain,
+        release: humanize::parse_time(release, variables)?.abs(),
+    })
+}
+
+fn parse_subtractive(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Subtractive, String> {
+    let mut synth = Subtractive::default();
+    let mut i = 0;
+    if let Some(waveform) = words.first().and_then(|w| Waveform::from_name(w)) {
+        synth.waveform = waveform;
+        i = 1;
+    }
+    while i < words.len() {
+        let setting = words[i].as_str();
+        let count = if setting == "envelope" || setting == "amp" {
+            4
+        } else {
+            1
+        };
+        let values = words
+            .get(i + 1..i + 1 + count)
+            .ok_or(format!("Missing value for '{}'", setting))?;
+        match setting {
+            "cutoff" => synth.cutoff = mix::parse_frequency(&values[0], variables)?,
+            "resonance" => {
+                synth.resonance = expr::evaluate(&values[0], variables)?;
+                if !(0.0..=1.0).contains(&synth.resonance) {
+                    return Err(format!("'{}' is not a valid resonance", synth.resonance));
+                }
+            }
+            "filter" => {
+                synth.model = match values[0].as_str() {
+                    "svf" => FilterModel::StateVariable,
+                    "ladder" => FilterModel::Ladder,
+                    other => return Err(format!("Unknown filter '{}'", other)),
+                }
+            }
+            "envelope" => synth.filter_envelope = parse_envelope(values, variables)?,
+            "amount" => synth.envelope_amount = expr::evaluate(&values[0], variables)?,
+            "keytrack" => synth.key_tracking = expr::evaluate(&values[0], variables)?,
+            "amp" => synth.amp_envelope = parse_envelope(values, variables)?,
+            other => return Err(format!("Unknown subtractive synth setting '{}'", other)),
+        }
+        i += 1 + count;
+    }
+    Ok(synth)
+}
+
+fn parse_fm(words: &[String], variables: &HashMap<String, Float>) -> Result<Fm, String> {
+    let mut groups = words.split(|w| w == "op");
+    let algorithm = match groups.next().unwrap_or_default() {
+        [] => Algorithm::Stack,
+        [name] => Algorithm::from_name(name).ok_or(format!("Unknown algorithm '{}'", name))?,
+        _ => return Err("Expected 'fm [algorithm] op ...'".to_owned()),
+    };
+    let mut operators = Vec::new();
+    for settings in groups {
+        let envelope = match settings.len() {
+            2 => Envelope::default(),
+            6 => parse_envelope(&settings[2..], variables)?,
+            _ => {
+                return Err(
+                    "Expected 'op <ratio> <index> [<attack> <decay> <sustain> <release>]'"
+                        .to_owned(),
+                )
+            }
+        };
+        let ratio = expr::evaluate(&settings[0], variables)?;
+        if ratio <= 0.0 {
+            return Err(format!("'{}' is not a valid frequency ratio", ratio));
+        }
+        operators.push(Operator {
+            ratio,
+            index: expr::evaluate(&settings[1], variables)?,
+            envelope,
+        });
+    }
+    if !(2..=6).contains(&operators.len()) {
+        return Err(format!(
+            "FM instruments take 2 to 6 operators, not {}",
+            operators.len()
+        ));
+    }
+    Ok(Fm {
+        algorithm,
+        operators,
+    })
+}
+
+/// Parses the arguments of a `sampler` line, which adds a zone to `sampler`: `sampler <file>
+/// [root <note>] [keys <lowest> <highest>] [velocity <lowest> <highest>]
+/// [loop <start> <end>|off] [release <time>]`, the file being relative to `directory`.
+///
+/// - `root` is the note the recording plays at. By default it is the note given by the
+///   `smpl` chunk of the file if there's one, or else middle C.
+/// - `keys` and `velocity` limit the notes and velocities played by the zone. Notes played by
+///   no zone are played by the zone whose root is the closest, preferably with the right
+///   velocity.
+/// - `loop` gives the part of the recording repeated while the note is held, in samples. By
+///   default it is the first loop of the `smpl` chunk if there's one.
+/// - `release` is how long notes take to fade out once let go, 20 ms by default.
+///
+/// `pitch` reads note names, and `tuning` is the frequency of A4. Recordings already in
+/// `files` aren't read again.
+pub fn parse_zone(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+    directory: &Path,
+    files: &mut Files,
+    pitch: impl Fn(&String) -> Result<Float, String>,
+    tuning: Float,
+) -> Result<Zone, String> {
+    let file = words.first().ok_or("Expected 'sampler <file>'")?;
+    let path = directory.join(file.trim_matches('"'));
+    let cannot_read =
+        |e: audiowave::WavImportError| format!("Error: cannot read '{}': {}", path.display(), e);
+    let recording = files.recording(&path).map_err(cannot_read)?;
+    let samplerate = recording.samplerate;
+    let sample = recording.sample.clone();
+    let is_valid_loop = |l: &SampleLoop| l.start < l.end && l.end <= sample.len();
+
+    let midi_note = recording.unity_note.unwrap_or(60);
+    let mut root = tuning * (2.0 as Float).powf((midi_note as Float - 69.0) / 12.0);
+    let mut sustain_loop = recording.sustain_loop.filter(is_valid_loop);
+    let mut keys = (0.0, Float::MAX);
+    let mut velocities = (0.0, Float::MAX);
+    let mut envelope = Envelope {
+        attack: 0.0,
+        decay: 0.0,
+        sustain: 1.0,
+        release: 0.02,
+    };
+    let mut i = 1;
+    while i < words.len() {
+        let setting = words[i].as_str();
+        let count = match setting {
+            "keys" | "velocity" => 2,
+            "loop" if words.get(i + 1).is_some_and(|w| w == "off") => 1,
+            "loop" => 2,
+            _ => 1,
+        };
+        let values = words
+            .get(i + 1..i + 1 + count)
+            .ok_or(format!("Missing value for '{}'", setting))?;
+        match setting {
+            "root" => root = pitch(&values[0])?,
+            "keys" => keys = (pitch(&values[0])?, pitch(&values[1])?),
+            "velocity" => {
+                velocities = (
+                    expr::evaluate(&values[0], variables)?,
+                    expr::evaluate(&values[1], variables)?,
+                )
+            }
+            "loop" if count == 1 => sustain_loop = None,
+            "loop" => {
+                let bound = |word: &String| {
+                    let v = expr::evaluate(word, variables)?;
+                    if v.fract() != 0.0 || v < 0.0 {
+                        return Err(format!("'{}' is not a valid loop point", v));
+                    }
+                    Ok(v as usize)
+                };
+                let points = SampleLoop {
+                    start: bound(&values[0])?,
+                    end: bound(&values[1])?,
+                };
+                if !is_valid_loop(&points) {
+                    return Err(format!(
+                        "The loop must end after it starts and within the {} samples of the file",
+                        sample.len()
+                    ));
+                }
+                sustain_loop = Some(points);
+            }
+            "release" => envelope.release = humanize::parse_time(&values[0], variables)?.abs(),
+            other => return Err(format!("Unknown sampler setting '{}'", other)),
+        }
+        i += 1 + count;
+    }
+    if root <= 0.0 {
+        return Err("The root can't be a rest".to_owned());
+    }
+    Ok(Zone {
+        sample,
+        samplerate,
+        root,
+        keys,
+        velocities,
+        sustain_loop,
+        envelope,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fm_takes_2_to_6_operators() {
+        let fm = |count: usize| {
+            let line = format!("fm{}", " op 1 1".repeat(count));
+            let words: Vec<String> = line.split_whitespace().map(String::from).collect();
+            parse(&words, &HashMap::new())
+        };
+        for count in [0, 1, 7, 8] {
+            assert!(fm(count).is_err_and(|e| e.contains("2 to 6 operators")));
+        }
+        for count in 2..=6 {
+            assert!(fm(count).is_ok());
+        }
+    }
+
+    #[test]
+    fn files_are_read_once() {
+        let dir = std::env::temp_dir().join(format!("amns-files-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let cycle = (0..64).map(|i| (i as Float / 32.0) - 1.0).collect();
+        AudioWave::from_samples(cycle, 44100)
+            .export_wav(&dir.join("saw.wav"))
+            .unwrap();
+        let words = |line: &str| {
+            line.split_whitespace()
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        let variables = HashMap::new();
+        let mut files = Files::default();
+        let zone = |files: &mut Files| {
+            parse_zone(
+                &words("saw.wav"),
+                &variables,
+                &dir,
+                files,
+                |_| Ok(440.0),
+                440.0,
+            )
+        };
+        let table = |files: &mut Files| {
+            parse_waveform(&words("table saw.wav frame 64"), &variables, &dir, files)
+        };
+        let first = zone(&mut files).unwrap();
+        table(&mut files).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        // the file is gone, so these only work if nothing is read again
+        let second = zone(&mut files).unwrap();
+        assert!(Arc::ptr_eq(&first.sample, &second.sample));
+        assert!(table(&mut files).is_ok());
+        assert!(zone(&mut Files::default()).is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use super::expr;
+use crate::definitions::Float;
+use std::collections::HashMap;
+
+/// A transformation applied to a section when it is jumped to, e.g. `jump theme transpose 5`.
+#[derive(Clone, Debug)]
+pub enum Transformation {
+    /// Shifts every pitch by a number of semitones.
+    Transpose(Float),
+    /// Mirrors every pitch around the given note.
+    Invert(String),
+    /// Multiplies the tempo, so `Tempo(2.0)` plays the section twice as fast.
+    Tempo(Float),
+    /// Plays the section backwards.
+    Retrograde,
+}
+
+/// Splits the words following the section name (and repetitions) of a `jump` line into the
+/// words of each transformation, like `transpose 5`. It only checks that they are complete,
+/// since their amounts can use variables, which are only known when they are played.
+pub fn split_transformations(words: &[String]) -> Result<Vec<&[String]>, String> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        let (len, missing) = match words[i].as_str() {
+            "transpose" => (2, "Missing amount of semitones to transpose"),
+            "tempo" => (2, "Missing tempo factor"),
+            "invert" if words.get(i + 1).map(|w| w.as_str()) == Some("around") => {
+                (3, "Missing note to invert around")
+            }
+            "invert" => (2, "Missing note to invert around"),
+            "retrograde" => (1, ""),
+            other => return Err(format!("Unknown transformation '{}'", other)),
+        };
+        let transformation = words.get(i..i + len).ok_or(missing)?;
+        result.push(transformation);
+        i += len;
+    }
+    Ok(result)
+}
+
+/// Parses the arguments of a `transform push` line. Amounts are expressions, evaluated with
+/// `variables`, and tempo factors can end with `x`, as in `tempo 2x`.
+pub fn parse_transformations(
+    words: &[String],
+    variables: &HashMap<String, Float>,
+) -> Result<Vec<Transformation>, String> {
+    let mut result = Vec::new();
+    for words in split_transformations(words)? {
+        let value = &words[words.len() - 1];
+        result.push(match words[0].as_str() {
+            "transpose" => match expr::evaluate(value, variables) {
+                Ok(v) => Transformation::Transpose(v),
+                Err(e) => return Err(format!("'{}' is not a valid transposition: {}", value, e)),
+            },
+            "tempo" => {
+                let factor = value
+                    .strip_suffix('x')
+                    .filter(|v| v.parse::<Float>().is_ok())
+                    .unwrap_or(value);
+                match expr::evaluate(factor, variables) {
+                    Ok(v) if v > 0.0 && v.is_finite() => Transformation::Tempo(v),
+                    _ => return Err(format!("'{}' is not a valid tempo factor", value)),
+                }
+            }
+            "invert" => Transformation::Invert(value.clone()),
+            _ => Transformation::Retrograde,
+        });
+    }
+    Ok(result)
+}
+
+/// Returns whether a line takes time to play (notes, rests, glissandos, trills and tuplets).
+fn is_timed(line: &str) -> bool {
+    let first = match line.split_whitespace().next() {
+        Some(w) => w.to_owned(),
+        None => return false,
+    };
+    first == "glissando" || first == "trill" || first == "tuplet" || super::is_pitch(&first)
+}
+
+/// Directives setting a part of the voice state, which `retrograde` moves along with the notes.
+const STATE_DIRECTIVES: [&str; 5] = ["octave", "bpm", "duration", "tuning", "intensity"];
+/// Directives picking the instrument. `sampler` lines add up, the others replace it.
+const INSTRUMENT_DIRECTIVES: [&str; 4] = ["waveform", "harmonics", "synth", "sampler"];
+
+/// The state directives in effect at some point of a section, counted from its start.
+#[derive(Clone, Default, PartialEq)]
+struct State {
+    /// The lines setting each part of the state, in the order the parts were first set.
+    parts: Vec<(String, Vec<String>)>,
+}
+
+impl State {
+    /// Takes `line` into account if it sets a part of the state, returning whether it did.
+    fn apply(&mut self, line: &str) -> bool {
+        let first = line.split_whitespace().next().unwrap_or("");
+        let part = if STATE_DIRECTIVES.contains(&first) {
+            first
+        } else if INSTRUMENT_DIRECTIVES.contains(&first) {
+            "instrument"
+        } else {
+            return false;
+        };
+        match self.parts.iter_mut().find(|(p, _)| p == part) {
+            Some((_, lines)) => {
+                let adds_zone =
+                    first == "sampler" && lines.last().is_some_and(|l| l.starts_with("sampler"));
+                if !adds_zone {
+                    lines.clear();
+                }
+                lines.push(line.to_owned());
+            }
+            None => self.parts.push((part.to_owned(), vec![line.to_owned()])),
+        }
+        true
+    }
+
+    /// Takes every line into account, except those between a `state push` and its `state pop`.
+    fn apply_all(&mut self, lines: &[String]) {
+        let mut depth = 0;
+        for line in lines {
+            if line.starts_with("state push") {
+                depth += 1;
+            } else if line.starts_with("state pop") {
+                depth -= 1;
+            } else if depth == 0 {
+                self.apply(line);
+            }
+        }
+    }
+
+    fn lines(&self) -> impl Iterator<Item = &String> {
+        self.parts.iter().flat_map(|(_, lines)| lines)
+    }
+}
+
+/// A part of a section that is moved as a whole by `retrograde`.
+enum Unit {
+    /// A line that doesn't take time, which stays where it is unless it sets a part of the state.
+    Directive(String),
+    Timed(String),
+    /// The lines between a `transform push` or `state push` and its pop, inclusive.
+    Block(Vec<String>),
+}
+
+fn is_push(line: &str) -> bool {
+    line.starts_with("transform push") || line.starts_with("state push")
+}
+
+fn is_pop(line: &str) -> bool {
+    line.starts_with("transform pop") || line.starts_with("state pop")
+}
+
+fn split_units(lines: &[String]) -> Vec<Unit> {
+    let mut units = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = &lines[i];
+        if is_push(line) {
+            let mut depth = 0;
+            let mut j = i;
+            while j < lines.len() {
+                if is_push(&lines[j]) {
+                    depth += 1;
+                } else if is_pop(&lines[j]) {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                j += 1;
+            }
+            let end = j.min(lines.len() - 1);
+            units.push(Unit::Block(lines[i..=end].to_vec()));
+            i = end + 1;
+        } else {
+            if is_timed(line) {
+                units.push(Unit::Timed(line.clone()));
+            } else {
+                units.push(Unit::Directive(line.clone()));
+            }
+            i += 1;
+        }
+    }
+    units
+}
+
+/// Reverses the notes of a tuplet line, keeping each with its duration.
+/// Invalid tuplets are returned as they are, to be reported when they are played.
+fn reverse_tuplet(line: &str) -> String {
+    let text = line.replace('{', " { ").replace('}', " } ");
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let (Some(open), Some(close)) = (
+        words.iter().position(|w| *w == "{"),
+        words.iter().rposition(|w| *w == "}"),
+    ) else {
+        return line.to_owned();
+    };
+    if close < open {
+        return line.to_owned();
+    }
+    // each item is a note or chord followed by its optional duration
+    let mut items: Vec<Vec<&str>> = Vec::new();
+    let mut joining = false;
+    for word in &words[open + 1..close] {
+        let starts_item = !joining && *word != "|" && super::is_pitch(&word.to_string());
+        joining = *word == "|";
+        match items.last_mut() {
+            Some(item) if !starts_item => item.push(word),
+            _ => items.push(vec![word]),
+        }
+    }
+    items.reverse();
+    let mut result: Vec<&str> = words[..=open].to_vec();
+    result.extend(items.into_iter().flatten());
+    result.extend(&words[close..]);
+    result.join(" ")
+}
+
+fn is_bar(line: &str) -> bool {
+    line.split_whitespace().next() == Some("bar")
+}
+
+/// Reverses the order of the timed lines of a section. Glissandos also have their first
+/// and last notes swapped, tuplets are reversed note by note, and transformed parts (from
+/// jumps inside the section) are reversed as a whole.
+///
+/// Bars are kept whole: the bars are played in reverse order, each with its notes reversed
+/// and closed by its `bar` line. Lines after the last `bar` line, which don't make a whole
+/// bar, are played first.
+///
+/// Each timed line keeps the state (octave, tempo, duration, tuning, intensity and
+/// instrument) it had: state directives are taken out of their place, and the state of each
+/// line is set again before it between `state push` and `state pop` lines, which restore the
+/// state the section started with. The section ends with the state it would end with if it
+/// was played forward. Other directives stay where they are within their bar.
+pub fn retrograde(lines: &[String]) -> Vec<String> {
+    let units = split_units(lines);
+    let mut state = State::default();
+    // the state each unit starts with
+    let mut states: Vec<State> = Vec::with_capacity(units.len());
+    for unit in &units {
+        states.push(state.clone());
+        match unit {
+            Unit::Directive(line) => {
+                state.apply(line);
+            }
+            Unit::Timed(_) => {}
+            Unit::Block(block) => state.apply_all(block),
+        }
+    }
+    let stateful = !state.parts.is_empty();
+
+    // indices of the units of each bar, without the `bar` lines, the last bar being the
+    // lines after the last `bar` line
+    let mut bars: Vec<Vec<usize>> = vec![Vec::new()];
+    for (i, unit) in units.iter().enumerate() {
+        match unit {
+            Unit::Directive(line) if is_bar(line) => bars.push(Vec::new()),
+            _ => bars.last_mut().expect("there is always a bar").push(i),
+        }
+    }
+    let rest = bars.pop().expect("there is always a bar");
+
+    let mut result = Vec::with_capacity(lines.len());
+    if stateful {
+        result.push("state push".to_owned());
+    }
+    // the state set since the last `state push`, unknown after a block changing it
+    let mut current = Some(State::default());
+    let closed = bars.into_iter().rev().map(|bar| (bar, true));
+    for (bar, is_closed) in std::iter::once((rest, false)).chain(closed) {
+        let mut moving: Vec<usize> = bar
+            .iter()
+            .copied()
+            .filter(|i| !matches!(units[*i], Unit::Directive(_)))
+            .collect();
+        for &i in &bar {
+            if let Unit::Directive(line) = &units[i] {
+                if !State::default().apply(line) {
+                    result.push(line.clone());
+                }
+                continue;
+            }
+            let j = moving
+                .pop()
+                .expect("there are as many moving units as slots");
+            if stateful && current.as_ref() != Some(&states[j]) {
+                if !matches!(&current, Some(c) if c.parts.is_empty()) {
+                    result.push("state pop".to_owned());
+                    result.push("state push".to_owned());
+                }
+                result.extend(states[j].lines().cloned());
+                current = Some(states[j].clone());
+            }
+            match &units[j] {
+                Unit::Timed(line) => {
+                    let mut words = super::split_by_whitespace(line);
+                    if words[0] == "glissando" && words.len() >= 3 {
+                        words.swap(1, 2);
+                    }
+                    if words[0] == "tuplet" {
+                        result.push(reverse_tuplet(line));
+                    } else {
+                        result.push(words.join(" "));
+                    }
+                }
+                Unit::Block(block) => {
+                    result.push(block[0].clone());
+                    if block.len() > 2 {
+                        result.extend(retrograde(&block[1..block.len() - 1]));
+                    }
+                    if block.len() > 1 {
+                        result.push(block[block.len() - 1].clone());
+                    }
+                    let mut changes = State::default();
+                    changes.apply_all(block);
+                    if !changes.parts.is_empty() {
+                        current = None;
+                    }
+                }
+                Unit::Directive(_) => unreachable!("directives are not moved"),
+            }
+        }
+        if is_closed {
+            result.push("bar".to_owned());
+        }
+    }
+    if stateful {
+        result.push("state pop".to_owned());
+        result.extend(state.lines().cloned());
+    }
+    result
+}
+
+/// Applies a stack of transformations to a frequency in Hz, innermost first.
+/// `resolve` turns the axis of an inversion into a frequency.
+pub fn apply_to_freq<F>(
+    freq: Float,
+    stack: &[Vec<Transformation>],
+    resolve: F,
+) -> Result<Float, String>
+where
+    F: Fn(&String) -> Result<Float, String>,
+{
+    if freq == 0.0 {
+        return Ok(freq);
+    }
+    let mut freq = freq;
+    for level in stack.iter().rev() {
+        for t in level {
+            match t {
+                Transformation::Transpose(v) => freq *= (2.0 as Float).powf(v / 12.0),
+                Transformation::Invert(axis) => {
+                    let axis = resolve(axis)?;
+                    freq = axis * axis / freq;
+                }
+                Transformation::Tempo(_) | Transformation::Retrograde => (),
+            }
+        }
+    }
+    Ok(freq)
+}
+
+/// Returns the combined tempo factor of a stack of transformations.
+pub fn tempo_factor(stack: &[Vec<Transformation>]) -> Float {
+    stack
+        .iter()
+        .flatten()
+        .map(|t| match t {
+            Transformation::Tempo(v) => *v,
+            _ => 1.0,
+        })
+        .product()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.split(';').map(|l| l.trim().to_owned()).collect()
+    }
+
+    #[test]
+    fn transformation_amounts_are_expressions() {
+        let variables = HashMap::from([("k".to_owned(), 2.0), ("max".to_owned(), 4.0)]);
+        let parse = |text: &str| {
+            let words: Vec<String> = text.split_whitespace().map(String::from).collect();
+            parse_transformations(&words, &variables)
+        };
+        let parsed = parse("transpose k*3 tempo 1.5x tempo max invert around C").unwrap();
+        assert!(matches!(
+            parsed[..],
+            [
+                Transformation::Transpose(t),
+                Transformation::Tempo(a),
+                Transformation::Tempo(b),
+                Transformation::Invert(_)
+            ] if t == 6.0 && a == 1.5 && b == 4.0
+        ));
+        assert!(parse("transpose j").is_err());
+        assert!(parse("tempo k-2").is_err());
+        assert!(parse("transpose").is_err());
+        assert!(parse("reverse").is_err());
+    }
+
+    #[test]
+    fn retrograde_keeps_directives_without_state_in_place() {
+        assert_eq!(retrograde(&lines("C;sync x;D q")), lines("D q;sync x;C"));
+    }
+
+    #[test]
+    fn retrograde_keeps_bars_whole() {
+        assert_eq!(
+            retrograde(&lines("C w;bar;D q;E q;F h;bar")),
+            lines("F h;E q;D q;bar;C w;bar")
+        );
+        assert_eq!(
+            retrograde(&lines("C;D q;bar;glissando E G h")),
+            lines("glissando G E h;D q;C;bar")
+        );
+    }
+
+    #[test]
+    fn retrograde_moves_the_state_with_the_notes() {
+        assert_eq!(
+            retrograde(&lines("octave 5;C;octave 3;D")),
+            lines("state push;octave 3;D;state pop;state push;octave 5;C;state pop;octave 3")
+        );
+        // the first note plays with the state the section starts with
+        assert_eq!(
+            retrograde(&lines("C;bpm 90;waveform square;D;sampler C4 piano.wav;E")),
+            lines(
+                "state push;bpm 90;sampler C4 piano.wav;E;\
+                 state pop;state push;bpm 90;waveform square;D;state pop;state push;C;\
+                 state pop;bpm 90;sampler C4 piano.wav"
+            )
+        );
+    }
+
+    #[test]
+    fn retrograde_reverses_tuplets() {
+        assert_eq!(
+            retrograde(&lines("tuplet 3:2 e {C D | F q E};A")),
+            lines("A;tuplet 3:2 e { E D | F q C }")
+        );
+    }
+
+    #[test]
+    fn retrograde_twice_plays_forward() {
+        let forward = lines("duration e;C;transform push transpose 2;octave 3;D;transform pop;E");
+        let twice = retrograde(&retrograde(&forward));
+        let timed: Vec<&String> = twice.iter().filter(|l| is_timed(l)).collect();
+        assert_eq!(timed, ["C", "D", "E"]);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::audiowave::AudioWave;
+use crate::definitions::Float;
+use crate::function::Function;
+use crate::instrument::Instrument;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A sound starting at some sample. It is only rendered when the timeline is mixed, so
+/// scheduling a voice doesn't keep every note's samples in memory.
+pub struct Event {
+    pub start: usize,
+    /// Length of the sound, in samples.
+    pub length: usize,
+    pub freq: Function,
+    pub amp: Function,
+    /// How hard the note is played, usually from 0 to 1, before chords share out the amplitude.
+    pub velocity: Float,
+    pub instrument: Arc<Instrument>,
+}
+
+impl Event {
+    /// Renders the sound, which can ring past `length` depending on the instrument.
+    pub fn render(&self, samplerate: Option<u32>) -> Option<AudioWave> {
+        self.instrument.render(
+            &self.freq,
+            &self.amp,
+            self.velocity,
+            self.length,
+            samplerate,
+        )
+    }
+}
+
+fn empty_wave(samplerate: Option<u32>) -> Option<AudioWave> {
+    AudioWave::new(
+        &Function::Const(0.0),
+        &Function::Const(0.0),
+        &0.0,
+        None,
+        samplerate,
+        None,
+    )
+}
+
+/// Renders consecutive events of a timeline into a wave starting at the sample where the
+/// first one starts, returning that sample along with the wave.
+fn render_segment(events: &[Event], samplerate: Option<u32>) -> Option<(usize, AudioWave)> {
+    let mut result = empty_wave(samplerate)?;
+    let first = events.iter().map(|e| e.start).min().unwrap_or(0);
+    for event in events {
+        result.mix_at_sample(&event.render(samplerate)?, event.start - first)?;
+    }
+    Some((first, result))
+}
+
+/// The sounds of a voice placed at absolute times. Unlike appending waves one after the
+/// other, events can overlap, so notes can start early or ring past the next one.
+///
+/// Events are grouped in segments, usually split where the voice syncs with others.
+/// Segments are rendered on their own and then added in order, so they can be rendered in
+/// parallel while giving the same samples.
+#[derive(Default)]
+pub struct Timeline {
+    events: Vec<Event>,
+    /// Index of the first event of each segment but the first one.
+    splits: Vec<usize>,
+    /// Sample until which the timeline lasts, even if no event is sounding.
+    end: usize,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline::default()
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.end = self.end.max(event.start + event.length);
+        self.events.push(event);
+    }
+
+    /// Starts a new segment, unless the current one is empty.
+    pub fn split(&mut self) {
+        let start = self.splits.last().copied().unwrap_or(0);
+        if self.events.len() > start {
+            self.splits.push(self.events.len());
+        }
+    }
+
+    /// Makes the timeline last at least until `sample`, like when a voice ends with rests.
+    pub fn extend_to(&mut self, sample: usize) {
+        self.end = self.end.max(sample);
+    }
+
+    pub fn get_end(&self) -> usize {
+        self.end
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &[Event]> {
+        let starts = std::iter::once(0).chain(self.splits.iter().copied());
+        let ends = self
+            .splits
+            .iter()
+            .copied()
+            .chain(std::iter::once(self.events.len()));
+        starts
+            .zip(ends)
+            .map(|(start, end)| &self.events[start..end])
+    }
+
+    /// Adds rendered segments, in order, into a wave lasting the whole timeline.
+    fn assemble(
+        &self,
+        segments: impl Iterator<Item = (usize, AudioWave)>,
+        samplerate: Option<u32>,
+    ) -> Option<AudioWave> {
+        let mut result = empty_wave(samplerate)?;
+        result.extend_to(self.end);
+        for (offset, wave) in segments {
+            result.mix_at_sample(&wave, offset)?;
+        }
+        Some(result)
+    }
+
+    /// Renders every event and mixes it into a single wave at its own sample offset.
+    pub fn mix(&self, samplerate: Option<u32>) -> Option<AudioWave> {
+        let segments = self
+            .segments()
+            .map(|events| render_segment(events, samplerate))
+            .collect::<Option<Vec<(usize, AudioWave)>>>()?;
+        self.assemble(segments.into_iter(), samplerate)
+    }
+}
+
+/// Mixes every timeline like `Timeline::mix` does, rendering the segments of all of them on
+/// up to `threads` threads. The waves are exactly the same whatever the number of threads.
+pub fn mix_parallel(
+    timelines: &[Timeline],
+    samplerate: Option<u32>,
+    threads: usize,
+) -> Option<Vec<AudioWave>> {
+    if threads <= 1 {
+        return timelines.iter().map(|t| t.mix(samplerate)).collect();
+    }
+    let jobs: Vec<(usize, &[Event])> = timelines
+        .iter()
+        .enumerate()
+        .flat_map(|(i, t)| t.segments().map(move |events| (i, events)))
+        .collect();
+    let results: Vec<Mutex<Option<(usize, AudioWave)>>> =
+        jobs.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..threads.min(jobs.len()) {
+            scope.spawn(|| loop {
+                let job = next.fetch_add(1, Ordering::Relaxed);
+                let Some((_, events)) = jobs.get(job) else {
+                    break;
+                };
+                let rendered = render_segment(events, samplerate);
+                *results[job].lock().expect("a rendering thread panicked") = rendered;
+            });
+        }
+    });
+
+    let mut rendered = jobs.iter().map(|(i, _)| *i).zip(results).peekable();
+    let mut waves = Vec::with_capacity(timelines.len());
+    for (i, timeline) in timelines.iter().enumerate() {
+        let mut segments = Vec::new();
+        while let Some((_, result)) = rendered.next_if(|(t, _)| *t == i) {
+            segments.push(result.into_inner().expect("a rendering thread panicked")?);
+        }
+        waves.push(timeline.assemble(segments.into_iter(), samplerate)?);
+    }
+    Some(waves)
+}